@@ -51,7 +51,10 @@ func main() {
 }
 
 func spawnProxy(c config.ConvertedConfig, buf []byte) {
+	// port, hostname and id of the request, the id is sent back with the tunnel
 	port := butils.ToUint16(buf[:2])
+	hostLen := int(buf[2])
+	requestId := butils.ToUint64(buf[3+hostLen : 11+hostLen])
 	localPort := config.ConnectorPort{}
 
 	for _, port_entry := range c.ConnectorInfo.Ports {
@@ -60,7 +63,7 @@ func spawnProxy(c config.ConvertedConfig, buf []byte) {
 		}
 	}
 
-	tunnel, err := spawnTunnel(port, c.Code, c.Connector)
+	tunnel, err := spawnTunnel(port, c.Code, requestId, c.Connector)
 	if err != nil {
 		fmt.Println("Tunnel error: ", err)
 		return
@@ -75,7 +78,7 @@ func spawnProxy(c config.ConvertedConfig, buf []byte) {
 	copyBidirectional(tunnel, local)
 }
 
-func spawnTunnel(port uint16, code uint64, tunnelIp string) (net.Conn, error) {
+func spawnTunnel(port uint16, code uint64, requestId uint64, tunnelIp string) (net.Conn, error) {
 	conn, err := net.Dial("tcp", tunnelIp)
 	if err != nil {
 		return nil, err
@@ -84,7 +87,10 @@ func spawnTunnel(port uint16, code uint64, tunnelIp string) (net.Conn, error) {
 	portBytes := butils.FromUint16(port)
 	codeBytes := butils.FromUint64(code)
 
+	// empty hostname, only shared ports are routed by it
 	tmpBytes := append(portBytes, codeBytes...)
+	tmpBytes = append(tmpBytes, 0)
+	tmpBytes = append(tmpBytes, butils.FromUint64(requestId)...)
 	conn.Write(tmpBytes)
 	return conn, nil
 }
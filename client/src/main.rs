@@ -2,18 +2,30 @@ use color_eyre::Result;
 use structs::{Config, ConvertedConfig};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::UdpSocket,
+    net::{TcpListener, UdpSocket},
 };
 use udpflow::UdpStreamRemote;
-use utils::{MultiStream, PortType};
+use utils::{
+    balance::Balancer, ConnectorPort, CopyOptions, Direction, ForwardTarget, MultiStream, PortType,
+};
 
+mod punch;
+mod socks;
 mod structs;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
 
-    let config = Config::load().await?.convert()?;
+    let config = Config::load().await?;
+    utils::init_udpflow();
+    if let Some(size) = config.buffer_size {
+        utils::buffer_pool::set_buffer_size(size);
+    }
+
+    let config = config.convert()?;
+    spawn_local_forwards(&config).await?;
+    punch::spawn_listeners(&config);
     spawn_connector_worker(config).await?;
 
     tokio::signal::ctrl_c().await?;
@@ -34,17 +46,11 @@ async fn spawn_connector_worker(config: ConvertedConfig) -> Result<()> {
 }
 
 async fn connector_worker(config: &ConvertedConfig) -> Result<()> {
-    let mut stream = tokio::net::TcpStream::connect((
-        config.connector_ip.clone(),
-        config.connector_port.clone(),
-    ))
-    .await?;
-    stream.set_nodelay(true)?;
-
-    let mut bytes = vec![];
-    bytes.write_u16(0).await?;
-    bytes.write_u64(config.code).await?;
-    stream.write_all(&bytes).await?;
+    let stream = config.connector_addr.connect(&config.transport).await?;
+    let mut stream = config
+        .connector_addr
+        .authenticate(stream, 0, config.code)
+        .await?;
 
     let encoded_data = config.connector.encode()?;
     stream.write_u16(encoded_data.len() as u16).await?;
@@ -54,35 +60,45 @@ async fn connector_worker(config: &ConvertedConfig) -> Result<()> {
     loop {
         let config = config.clone();
 
+        // the request id goes back with the tunnel so the server hands it to
+        // the connection which asked for it
         let port = stream.read_u16().await?;
+        let hostname = utils::read_hostname(&mut stream).await?;
+        let request_id = stream.read_u64().await?;
+        if port == 0 {
+            spawn_service_tunnel(config, hostname, request_id);
+            continue;
+        }
+
+        let hostname = Some(hostname).filter(|h| !h.is_empty());
         let local_port = match config
             .connector
             .ports
             .iter()
-            .find(|p| p.port_remote == port)
+            .find(|p| {
+                p.direction == Direction::Remote && p.port_remote == port && p.hostname == hostname
+            })
             .cloned()
         {
             Some(p) => p,
             None => {
-                eprintln!("Unknown port: {}", port);
+                eprintln!("Unknown port: {} ({:?})", port, hostname);
                 continue;
             }
         };
 
         tokio::spawn(async move {
             let tunnel = MultiStream::connect_and_setup(
-                &config.connector_ip,
-                config.connector_port,
-                local_port.tunnel_type,
-                port,
+                &config.connector_addr,
+                &local_port,
                 config.code,
+                request_id,
             )
             .await?;
 
-            if local_port.port_type == PortType::Tcp {
-                proxy_tcp(tunnel, &local_port.local_ip, local_port.port_local).await?;
-            } else if local_port.port_type == PortType::Udp {
-                proxy_udp(tunnel, &local_port.local_ip, local_port.port_local).await?;
+            match local_port.port_type {
+                PortType::Udp => proxy_udp(tunnel, &local_port).await?,
+                _ => proxy_tcp(tunnel, &local_port).await?,
             }
 
             Ok::<_, color_eyre::Report>(())
@@ -90,18 +106,190 @@ async fn connector_worker(config: &ConvertedConfig) -> Result<()> {
     }
 }
 
-async fn proxy_tcp(tunnel: MultiStream, ip: &str, local_port: u16) -> Result<()> {
-    let local = tokio::net::TcpStream::connect((ip, local_port)).await?;
+/// Dials back tunnel of an exposed service for its new consumer, the server
+/// asks only for names it accepted
+fn spawn_service_tunnel(config: ConvertedConfig, name: String, request_id: u64) {
+    let local_port =
+        match config.connector.ports.iter().find(|p| {
+            p.direction == Direction::Service && p.service.as_deref() == Some(name.as_str())
+        }) {
+            Some(p) => p.clone(),
+            None => {
+                eprintln!("Unknown service: {}", name);
+                return;
+            }
+        };
+
+    tokio::spawn(async move {
+        let target = ForwardTarget::ServiceTunnel {
+            name,
+            token: config.connector.token.clone().unwrap_or_default(),
+            request_id,
+        };
+        let tunnel =
+            MultiStream::connect_forward(&config.connector_addr, &local_port, config.code, &target)
+                .await?;
+
+        proxy_tcp(tunnel, &local_port).await
+    });
+}
+
+/// Listens on local and dynamic forward ports, every connection gets its
+/// own tunnel which the server connects to the target
+async fn spawn_local_forwards(config: &ConvertedConfig) -> Result<()> {
+    for port in config.connector.ports.iter() {
+        if matches!(port.direction, Direction::Remote | Direction::Service) {
+            continue;
+        }
+
+        let listener = TcpListener::bind((port.local_ip.as_str(), port.port_local)).await?;
+        match port.direction {
+            Direction::Dynamic => println!("SOCKS5 proxy on {}:{}", port.local_ip, port.port_local),
+            _ if port.service.is_some() => println!(
+                "Forwarding {}:{} to service {}",
+                port.local_ip,
+                port.port_local,
+                port.service.as_deref().unwrap_or_default()
+            ),
+            _ => println!(
+                "Forwarding {}:{} to {}:{}",
+                port.local_ip,
+                port.port_local,
+                port.remote_host.as_deref().unwrap_or("127.0.0.1"),
+                port.port_remote
+            ),
+        }
+
+        let config = config.clone();
+        let port = port.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = local_forward_worker(&listener, &config, &port).await {
+                    eprintln!("Error in local forward worker: {}", e);
+                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn local_forward_worker(
+    listener: &TcpListener,
+    config: &ConvertedConfig,
+    port: &ConnectorPort,
+) -> Result<()> {
+    loop {
+        let (local, _) = listener.accept().await?;
+        local.set_nodelay(true)?;
+
+        let config = config.clone();
+        let port = port.clone();
+        tokio::spawn(async move {
+            if port.direction == Direction::Dynamic {
+                return socks::serve(local, &config, &port).await;
+            }
+
+            let direct = match port.direct {
+                true => punch::open_stream(&config, &port).await,
+                false => None,
+            };
+            let tunnel = match direct {
+                Some(tunnel) => tunnel,
+                None => {
+                    let target = match &port.service {
+                        Some(name) => ForwardTarget::Service {
+                            name: name.clone(),
+                            token: config.connector.token.clone().unwrap_or_default(),
+                        },
+                        None => ForwardTarget::Tcp(
+                            port.remote_host
+                                .clone()
+                                .unwrap_or(String::from("127.0.0.1")),
+                            port.port_remote,
+                        ),
+                    };
+
+                    MultiStream::connect_forward(
+                        &config.connector_addr,
+                        &port,
+                        config.code,
+                        &target,
+                    )
+                    .await?
+                }
+            };
+
+            tunnel
+                .copy_bidirectional_tcp(
+                    local,
+                    &CopyOptions {
+                        idle_timeout: port.idle_timeout(),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+
+            Ok::<_, color_eyre::Report>(())
+        });
+    }
+}
+
+/// Connects to local backend picked by the port's balance, the next ones are
+/// tried if it's down
+async fn proxy_tcp(tunnel: MultiStream, port: &ConnectorPort) -> Result<()> {
+    let balancer = Balancer::get(&port.backends());
+
+    let mut connected = None;
+    for index in balancer.order(port.balance) {
+        let backend = balancer.backend(index);
+        let connect = tokio::net::TcpStream::connect(backend);
+        match tokio::time::timeout(port.connect_timeout(), connect).await {
+            Ok(Ok(local)) => {
+                connected = Some((local, balancer.track(index)));
+                break;
+            }
+            Ok(Err(e)) => eprintln!("Local connect failed ({}): {}", backend, e),
+            Err(_) => eprintln!("Local connect timed out ({})", backend),
+        }
+        balancer.fail(index);
+    }
+
+    let (local, _guard) = match connected {
+        Some(connected) => connected,
+        None => return Ok(()),
+    };
     local.set_nodelay(true)?;
 
-    tunnel.copy_bidirectional(local).await?;
+    tunnel
+        .copy_bidirectional_tcp(
+            local,
+            &CopyOptions {
+                idle_timeout: port.idle_timeout(),
+                ..Default::default()
+            },
+        )
+        .await?;
     Ok(())
 }
 
-async fn proxy_udp(tunnel: MultiStream, ip: &str, local_port: u16) -> Result<()> {
+async fn proxy_udp(tunnel: MultiStream, port: &ConnectorPort) -> Result<()> {
     let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    let local = UdpStreamRemote::new(socket, format!("{}:{}", ip, local_port).parse()?);
+    let local = UdpStreamRemote::new(
+        socket,
+        format!("{}:{}", port.local_ip, port.port_local).parse()?,
+    );
 
-    tunnel.copy_bidirectional(local).await?;
+    // same timeout as on the server, so both ends of the flow close together
+    tunnel
+        .copy_bidirectional(
+            local,
+            &CopyOptions {
+                idle_timeout: port.idle_timeout(),
+                ..Default::default()
+            },
+        )
+        .await?;
     Ok(())
 }
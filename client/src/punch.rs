@@ -0,0 +1,353 @@
+use crate::structs::ConvertedConfig;
+use color_eyre::Result;
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    time::Instant,
+};
+use utils::{
+    quic::{self, QuicPeer},
+    socks::Address,
+    ConnectorPort, Direction, ForwardTarget, MultiStream,
+};
+
+/// Seconds to open direct path before falling back to the relay
+const PUNCH_TIMEOUT: u64 = 5;
+
+/// Seconds after failed direct path before it's tried again, connections
+/// are relayed meanwhile
+const PUNCH_RETRY: u64 = 30;
+
+/// Roles in punch datagrams, they must match the server
+const CONSUMER: u8 = 0;
+const PROVIDER: u8 = 1;
+
+/// Direct QUIC connection to provider of a service, shared by all
+/// connections to that service. Both sides pin certificate of the other one
+/// by fingerprint they got from the rendezvous.
+#[derive(Default)]
+struct DirectPath {
+    peer: Option<QuicPeer>,
+    retry_at: Option<Instant>,
+}
+
+static PATHS: Mutex<BTreeMap<String, Arc<tokio::sync::Mutex<DirectPath>>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Opens stream to the service over direct path, opening the path first if
+/// there's none. None means the connection has to be relayed.
+pub async fn open_stream(config: &ConvertedConfig, port: &ConnectorPort) -> Option<MultiStream> {
+    let name = port.service.clone()?;
+    let path = PATHS.lock().ok()?.entry(name.clone()).or_default().clone();
+    let mut path = path.lock().await;
+
+    let alive = path.peer.as_ref().is_some_and(|peer| !peer.is_closed());
+    if !alive {
+        if path.retry_at.is_some_and(|at| at > Instant::now()) {
+            return None;
+        }
+
+        let timeout = Duration::from_secs(PUNCH_TIMEOUT);
+        let res = tokio::time::timeout(timeout, connect(config, port, &name))
+            .await
+            .unwrap_or_else(|_| Err(color_eyre::eyre::eyre!("timed out")));
+
+        match res {
+            Ok(peer) => {
+                println!("Direct path to service {} opened", name);
+                path.peer = Some(peer);
+                path.retry_at = None;
+            }
+            Err(e) => {
+                eprintln!("Direct path to service {} failed, relaying: {}", name, e);
+                path.peer = None;
+                path.retry_at = Some(Instant::now() + Duration::from_secs(PUNCH_RETRY));
+                return None;
+            }
+        }
+    }
+
+    let peer = path.peer.as_ref()?;
+    match open(peer).await {
+        Ok(stream) => Some(stream),
+        Err(e) => {
+            eprintln!("Direct path to service {} lost, relaying: {}", name, e);
+            path.peer = None;
+            None
+        }
+    }
+}
+
+/// Starts rendezvous as consumer and connects to the provider
+async fn connect(config: &ConvertedConfig, port: &ConnectorPort, name: &str) -> Result<QuicPeer> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let identity = quic::Identity::generate()?;
+    let target = ForwardTarget::Punch {
+        name: name.to_string(),
+        token: config.connector.token.clone().unwrap_or_default(),
+        id: 0,
+        fingerprint: identity.fingerprint(),
+    };
+    let mut stream =
+        MultiStream::connect_forward(&config.connector_addr, port, config.code, &target).await?;
+
+    let id = stream.read_u64().await?;
+    let (peer_addr, fingerprint) = rendezvous(config, &socket, &mut stream, id, CONSUMER).await?;
+
+    // this opens our NAT for provider's datagrams, retransmitted QUIC
+    // handshake gets through once provider's datagrams opened its NAT
+    socket
+        .send_to(&punch_datagram(id, CONSUMER), peer_addr)
+        .await?;
+    let endpoint = quic::peer_endpoint(socket.into_std()?, &identity, None)?;
+    let peer = quic::connect_peer(&endpoint, peer_addr, &identity, &fingerprint).await?;
+
+    Ok(peer)
+}
+
+/// Keeps direct services registered on the server, their providers join
+/// rendezvous started by consumers
+pub fn spawn_listeners(config: &ConvertedConfig) {
+    let ports = config
+        .connector
+        .ports
+        .iter()
+        .filter(|p| p.direction == Direction::Service && p.direct);
+
+    for port in ports {
+        let config = config.clone();
+        let port = port.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = listen(&config, &port).await {
+                    eprintln!("Error in direct path listener: {}", e);
+                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                }
+            }
+        });
+    }
+}
+
+async fn listen(config: &ConvertedConfig, port: &ConnectorPort) -> Result<()> {
+    let name = port.service.clone().unwrap_or_default();
+    let target = ForwardTarget::PunchListen {
+        name: name.clone(),
+        token: config.connector.token.clone().unwrap_or_default(),
+    };
+    let mut stream =
+        MultiStream::connect_forward(&config.connector_addr, port, config.code, &target).await?;
+
+    loop {
+        let id = stream.read_u64().await?;
+
+        let config = config.clone();
+        let port = port.clone();
+        let name = name.clone();
+        tokio::spawn(async move {
+            if let Err(e) = accept(&config, &port, &name, id).await {
+                eprintln!("Direct path for service {} failed: {}", name, e);
+            }
+        });
+    }
+}
+
+/// Joins rendezvous as provider, accepts connection of the consumer and
+/// serves its streams until it's closed
+async fn accept(config: &ConvertedConfig, port: &ConnectorPort, name: &str, id: u64) -> Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let identity = quic::Identity::generate()?;
+    let target = ForwardTarget::Punch {
+        name: name.to_string(),
+        token: config.connector.token.clone().unwrap_or_default(),
+        id,
+        fingerprint: identity.fingerprint(),
+    };
+    let mut stream =
+        MultiStream::connect_forward(&config.connector_addr, port, config.code, &target).await?;
+
+    stream.read_u64().await?;
+    let (peer_addr, fingerprint) = rendezvous(config, &socket, &mut stream, id, PROVIDER).await?;
+
+    // consumer's handshake gets through our NAT only after we sent something
+    // to it, so keep sending until it's accepted
+    let socket = socket.into_std()?;
+    let puncher = UdpSocket::from_std(socket.try_clone()?)?;
+    let endpoint = quic::peer_endpoint(socket, &identity, Some(&fingerprint))?;
+    let punching = tokio::spawn(async move {
+        let datagram = punch_datagram(id, PROVIDER);
+        loop {
+            let _ = puncher.send_to(&datagram, peer_addr).await;
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+    });
+
+    // connections from anyone else who found the port are ignored
+    let accept = async {
+        loop {
+            let connecting = endpoint
+                .accept()
+                .await
+                .ok_or_else(|| color_eyre::eyre::eyre!("Endpoint closed"))?;
+
+            if connecting.remote_address() == peer_addr {
+                return Ok::<_, color_eyre::Report>(connecting.await?);
+            }
+        }
+    };
+    let connection = tokio::time::timeout(Duration::from_secs(PUNCH_TIMEOUT), accept).await;
+    punching.abort();
+
+    let peer = match connection {
+        Ok(connection) => QuicPeer::new(connection?),
+        Err(_) => color_eyre::eyre::bail!("consumer didn't connect"),
+    };
+    println!("Direct path for service {} opened ({})", name, peer_addr);
+
+    serve(&peer, port).await;
+
+    println!("Direct path for service {} closed", name);
+    Ok(())
+}
+
+/// Opens stream on direct path. The other side learns about QUIC stream
+/// only with its first bytes, so it starts with marker like tunnels do
+/// (services speaking first would wait forever otherwise).
+async fn open(peer: &QuicPeer) -> Result<MultiStream> {
+    let mut stream = peer.open_bi().await?;
+    stream.write_u8(quic::STREAM_TUNNEL).await?;
+
+    Ok(MultiStream::Quic(Box::new(stream)))
+}
+
+/// Proxies streams of direct path to the local service until it's closed
+async fn serve(peer: &QuicPeer, port: &ConnectorPort) {
+    while let Ok(mut stream) = peer.accept_bi().await {
+        let port = port.clone();
+        tokio::spawn(async move {
+            if stream.read_u8().await? != quic::STREAM_TUNNEL {
+                return Ok(());
+            }
+
+            crate::proxy_tcp(MultiStream::Quic(Box::new(stream)), &port).await
+        });
+    }
+}
+
+/// Sends punch datagrams to the server until it saw both sides, it answers
+/// with public endpoint of the other one and fingerprint of its certificate
+async fn rendezvous(
+    config: &ConvertedConfig,
+    socket: &UdpSocket,
+    stream: &mut MultiStream,
+    id: u64,
+    role: u8,
+) -> Result<(SocketAddr, String)> {
+    let punch_port = config
+        .connector_addr
+        .punch_port
+        .ok_or_else(|| color_eyre::eyre::eyre!("Punch port not set"))?;
+    let server = tokio::net::lookup_host((config.connector_addr.ip.as_str(), punch_port))
+        .await?
+        .next()
+        .ok_or_else(|| color_eyre::eyre::eyre!("Could not resolve {}", config.connector_addr.ip))?;
+
+    let datagram = punch_datagram(id, role);
+    let address = {
+        let read = Address::read(stream);
+        tokio::pin!(read);
+
+        loop {
+            socket.send_to(&datagram, server).await?;
+            tokio::select! {
+                address = &mut read => break address?,
+                _ = tokio::time::sleep(tokio::time::Duration::from_millis(100)) => {}
+            }
+        }
+    };
+    let fingerprint = utils::read_hostname(stream).await?;
+
+    match address {
+        Address::Ip(addr) => Ok((addr, fingerprint)),
+        Address::Domain(..) => color_eyre::eyre::bail!("Invalid peer address: {}", address),
+    }
+}
+
+fn punch_datagram(id: u64, role: u8) -> Vec<u8> {
+    let mut datagram = id.to_be_bytes().to_vec();
+    datagram.push(role);
+    datagram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quic::Identity;
+    use tokio::net::TcpListener;
+
+    /// Direct path between two loopback endpoints, consumer's side first
+    async fn direct_path() -> (QuicPeer, QuicPeer) {
+        let (consumer, provider) = (Identity::generate().unwrap(), Identity::generate().unwrap());
+        let consumer_socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let provider_socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let provider_addr = provider_socket.local_addr().unwrap();
+
+        let accepting =
+            quic::peer_endpoint(provider_socket, &provider, Some(&consumer.fingerprint())).unwrap();
+        let connecting = quic::peer_endpoint(consumer_socket, &consumer, None).unwrap();
+
+        let accepted = tokio::spawn(async move { accepting.accept().await.unwrap().await });
+        let consumer_peer = quic::connect_peer(
+            &connecting,
+            provider_addr,
+            &consumer,
+            &provider.fingerprint(),
+        )
+        .await
+        .unwrap();
+        let provider_peer = QuicPeer::new(accepted.await.unwrap().unwrap());
+
+        (consumer_peer, provider_peer)
+    }
+
+    fn service_port(local: u16) -> ConnectorPort {
+        serde_json::from_value(serde_json::json!({
+            "port_remote": 0,
+            "port_local": local,
+            "local_ip": "127.0.0.1",
+            "port_type": "Tcp",
+            "tunnel_type": "Quic",
+            "direction": "Service",
+            "direct": true,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn service_speaking_first_is_served() {
+        let service = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = service_port(service.local_addr().unwrap().port());
+        tokio::spawn(async move {
+            let (mut local, _) = service.accept().await.unwrap();
+            local.write_all(b"220 banner\r\n").await.unwrap();
+            local.read_u8().await.ok();
+        });
+
+        let (consumer, provider) = direct_path().await;
+        tokio::spawn(async move { serve(&provider, &port).await });
+
+        let mut stream = open(&consumer).await.unwrap();
+        let mut banner = [0; 12];
+        let read = stream.read_exact(&mut banner);
+        tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .expect("banner didn't arrive")
+            .unwrap();
+        assert_eq!(&banner, b"220 banner\r\n");
+    }
+}
@@ -0,0 +1,173 @@
+use crate::structs::ConvertedConfig;
+use color_eyre::Result;
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+use utils::{
+    socks::{self, Address},
+    ConnectorPort, CopyOptions, ForwardRefused, ForwardTarget, MultiStream, BUFFER_SIZE,
+};
+
+/// Serves one client of dynamic forward port (SOCKS5 without
+/// authentication), CONNECT and UDP ASSOCIATE requests are sent through the
+/// server which reaches their destinations
+pub async fn serve(
+    mut local: TcpStream,
+    config: &ConvertedConfig,
+    port: &ConnectorPort,
+) -> Result<()> {
+    let version = local.read_u8().await?;
+    if version != socks::VERSION {
+        color_eyre::eyre::bail!("Invalid SOCKS version: {}", version);
+    }
+
+    let methods_len = local.read_u8().await?;
+    let mut methods = vec![0; methods_len as usize];
+    local.read_exact(&mut methods).await?;
+    if !methods.contains(&socks::NO_AUTH) {
+        local
+            .write_all(&[socks::VERSION, socks::NO_ACCEPTABLE_METHODS])
+            .await?;
+        return Ok(());
+    }
+    local.write_all(&[socks::VERSION, socks::NO_AUTH]).await?;
+
+    let mut request = [0; 3];
+    local.read_exact(&mut request).await?;
+    let address = Address::read(&mut local).await?;
+
+    match request[1] {
+        socks::CONNECT => connect(local, config, port, address).await,
+        socks::UDP_ASSOCIATE => associate(local, config, port).await,
+        command => {
+            reply(
+                &mut local,
+                socks::COMMAND_NOT_SUPPORTED,
+                &Address::unspecified(),
+            )
+            .await?;
+            eprintln!("Unsupported SOCKS command: {}", command);
+            Ok(())
+        }
+    }
+}
+
+async fn reply(local: &mut TcpStream, status: u8, bound: &Address) -> Result<()> {
+    let mut bytes = vec![socks::VERSION, status, 0x00];
+    bound.encode(&mut bytes)?;
+
+    local.write_all(&bytes).await?;
+    local.flush().await?;
+    Ok(())
+}
+
+/// Reply code for failed forward, the server sends its own one
+fn failure_status(e: &color_eyre::Report) -> u8 {
+    e.downcast_ref::<ForwardRefused>()
+        .map_or(socks::GENERAL_FAILURE, |refused| refused.0)
+}
+
+async fn connect(
+    mut local: TcpStream,
+    config: &ConvertedConfig,
+    port: &ConnectorPort,
+    address: Address,
+) -> Result<()> {
+    let target = ForwardTarget::Tcp(address.host(), address.port());
+    let tunnel = match MultiStream::connect_forward(
+        &config.connector_addr,
+        port,
+        config.code,
+        &target,
+    )
+    .await
+    {
+        Ok(tunnel) => tunnel,
+        Err(e) => {
+            eprintln!("SOCKS connect to {} failed: {}", address, e);
+            return reply(&mut local, failure_status(&e), &Address::unspecified()).await;
+        }
+    };
+    reply(&mut local, socks::SUCCEEDED, &Address::unspecified()).await?;
+
+    tunnel
+        .copy_bidirectional_tcp(
+            local,
+            &CopyOptions {
+                idle_timeout: port.idle_timeout(),
+                ..Default::default()
+            },
+        )
+        .await
+}
+
+/// Relays datagrams of the client between local UDP socket and the tunnel,
+/// association ends with its TCP connection
+async fn associate(
+    mut local: TcpStream,
+    config: &ConvertedConfig,
+    port: &ConnectorPort,
+) -> Result<()> {
+    let mut tunnel = match MultiStream::connect_forward(
+        &config.connector_addr,
+        port,
+        config.code,
+        &ForwardTarget::Udp,
+    )
+    .await
+    {
+        Ok(tunnel) => tunnel,
+        Err(e) => {
+            eprintln!("SOCKS UDP associate failed: {}", e);
+            return reply(&mut local, failure_status(&e), &Address::unspecified()).await;
+        }
+    };
+
+    // relay listens where the client reached us, so it can reach it as well
+    let socket = UdpSocket::bind((local.local_addr()?.ip(), 0)).await?;
+    reply(
+        &mut local,
+        socks::SUCCEEDED,
+        &Address::Ip(socket.local_addr()?),
+    )
+    .await?;
+
+    let client_ip = local.peer_addr()?.ip();
+    let mut client: Option<SocketAddr> = None;
+
+    let local_buf = &mut vec![0; BUFFER_SIZE];
+    let tunnel_buf = &mut vec![0; BUFFER_SIZE];
+    let mut control = [0; 1];
+    loop {
+        tokio::select! {
+            res = socket.recv_from(&mut local_buf[..]) => {
+                let (n, addr) = res?;
+                // only the client which asked for the association can use it
+                if addr.ip() != client_ip {
+                    continue;
+                }
+                client = Some(addr);
+
+                tunnel.write_all(&local_buf[..n]).await?;
+                tunnel.flush().await?;
+            }
+            res = tunnel.read(&mut tunnel_buf[..]) => {
+                let n = res?;
+                if n == 0 {
+                    return Ok(());
+                }
+
+                if let Some(client) = client {
+                    socket.send_to(&tunnel_buf[..n], client).await?;
+                }
+            }
+            res = local.read(&mut control) => {
+                if res? == 0 {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
@@ -1,18 +1,48 @@
 use color_eyre::Result;
 use serde::{Deserialize, Serialize};
-use utils::{ConnectorInfo, ConnectorPort, PortType};
+use utils::{
+    balance::Balance, compression::Compression, proxy::Proxy, quic, ConnectorAddr, ConnectorInfo,
+    ConnectorPort, Direction, PortType, MAX_UDP_FLOWS, UDP_IDLE_TIMEOUT,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Config {
     pub connector: String,
     pub code: u64,
+    pub proxy: Option<String>,
+
+    #[serde(rename = "quicPort")]
+    pub quic_port: Option<u16>,
+
+    #[serde(rename = "quicFingerprint")]
+    pub quic_fingerprint: Option<String>,
+
+    #[serde(rename = "punchPort")]
+    pub punch_port: Option<u16>,
+
+    #[serde(rename = "tunnelTimeout")]
+    pub tunnel_timeout: Option<u64>,
+
+    #[serde(rename = "connectTimeout")]
+    pub connect_timeout: Option<u64>,
+
+    #[serde(rename = "idleTimeout")]
+    pub idle_timeout: Option<u64>,
+
+    #[serde(rename = "bufferSize")]
+    pub buffer_size: Option<usize>,
+
+    pub noise: Option<bool>,
+    pub token: Option<String>,
 
     pub ports: Vec<ConfigPort>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConfigPort {
+    #[serde(default)]
     pub remote: u16,
+    #[serde(default)]
     pub local: u16,
     pub ip: Option<String>,
 
@@ -21,6 +51,51 @@ pub struct ConfigPort {
 
     #[serde(rename = "tunnelType")]
     pub tunnel_type: Option<String>,
+
+    pub hostname: Option<String>,
+
+    #[serde(rename = "terminateTls")]
+    pub terminate_tls: Option<bool>,
+
+    #[serde(rename = "udpIdleTimeout")]
+    pub udp_idle_timeout: Option<u64>,
+
+    #[serde(rename = "maxUdpFlows")]
+    pub max_udp_flows: Option<usize>,
+
+    #[serde(rename = "tunnelTimeout")]
+    pub tunnel_timeout: Option<u64>,
+
+    #[serde(rename = "connectTimeout")]
+    pub connect_timeout: Option<u64>,
+
+    #[serde(rename = "idleTimeout")]
+    pub idle_timeout: Option<u64>,
+
+    #[serde(rename = "bandwidthLimit")]
+    pub bandwidth_limit: Option<u64>,
+
+    #[serde(rename = "maxConnections")]
+    pub max_connections: Option<usize>,
+
+    #[serde(rename = "maxConnectionsPerIp")]
+    pub max_connections_per_ip: Option<usize>,
+
+    #[serde(rename = "connectionRate")]
+    pub connection_rate: Option<u32>,
+
+    #[serde(rename = "connectionRatePerIp")]
+    pub connection_rate_per_ip: Option<u32>,
+    pub compression: Option<String>,
+    pub direction: Option<String>,
+
+    #[serde(rename = "remoteHost")]
+    pub remote_host: Option<String>,
+    pub service: Option<String>,
+    pub direct: Option<bool>,
+    pub backends: Option<Vec<String>>,
+    pub balance: Option<String>,
+    pub pool: Option<bool>,
 }
 
 #[derive(Debug, Clone)]
@@ -28,8 +103,8 @@ pub struct ConvertedConfig {
     pub connector: ConnectorInfo,
 
     pub code: u64,
-    pub connector_ip: String,
-    pub connector_port: u16,
+    pub connector_addr: ConnectorAddr,
+    pub transport: PortType,
 }
 
 impl Config {
@@ -52,12 +127,42 @@ impl Config {
                 let config = Config {
                     connector: String::from("server:1337"),
                     code: 123213123123123,
+                    proxy: None,
+                    quic_port: None,
+                    quic_fingerprint: None,
+                    punch_port: None,
+                    tunnel_timeout: None,
+                    connect_timeout: None,
+                    idle_timeout: None,
+                    buffer_size: None,
+                    noise: None,
+                    token: None,
                     ports: vec![ConfigPort {
                         remote: 8080,
                         local: 80,
                         ip: Some(String::from("127.0.0.1")),
                         _type: Some(String::from("TCP")),
                         tunnel_type: Some(String::from("tcp")),
+                        hostname: None,
+                        terminate_tls: None,
+                        udp_idle_timeout: None,
+                        max_udp_flows: None,
+                        tunnel_timeout: None,
+                        connect_timeout: None,
+                        idle_timeout: None,
+                        bandwidth_limit: None,
+                        max_connections: None,
+                        max_connections_per_ip: None,
+                        connection_rate: None,
+                        connection_rate_per_ip: None,
+                        compression: None,
+                        direction: None,
+                        remote_host: None,
+                        service: None,
+                        direct: None,
+                        backends: None,
+                        balance: None,
+                        pool: None,
                     }],
                 };
 
@@ -75,6 +180,37 @@ impl Config {
         config.code = std::env::var("LF_CODE")
             .unwrap_or(String::from("123213123123123"))
             .parse::<u64>()?;
+        config.proxy = std::env::var("LF_PROXY").ok();
+        config.quic_port = match std::env::var("LF_QUIC_PORT") {
+            Ok(port) => Some(port.parse::<u16>()?),
+            Err(_) => None,
+        };
+        config.quic_fingerprint = std::env::var("LF_QUIC_FINGERPRINT").ok();
+        config.punch_port = match std::env::var("LF_PUNCH_PORT") {
+            Ok(port) => Some(port.parse::<u16>()?),
+            Err(_) => None,
+        };
+        config.tunnel_timeout = match std::env::var("LF_TUNNEL_TIMEOUT") {
+            Ok(timeout) => Some(timeout.parse::<u64>()?),
+            Err(_) => None,
+        };
+        config.connect_timeout = match std::env::var("LF_CONNECT_TIMEOUT") {
+            Ok(timeout) => Some(timeout.parse::<u64>()?),
+            Err(_) => None,
+        };
+        config.idle_timeout = match std::env::var("LF_IDLE_TIMEOUT") {
+            Ok(timeout) => Some(timeout.parse::<u64>()?),
+            Err(_) => None,
+        };
+        config.buffer_size = match std::env::var("LF_BUFFER_SIZE") {
+            Ok(size) => Some(size.parse::<usize>()?),
+            Err(_) => None,
+        };
+        config.noise = match std::env::var("LF_NOISE") {
+            Ok(noise) => Some(noise.parse::<bool>()?),
+            Err(_) => None,
+        };
+        config.token = std::env::var("LF_TOKEN").ok();
 
         let mut ports: Vec<ConfigPort> = Vec::new();
         for (key, value) in std::env::vars() {
@@ -112,6 +248,26 @@ impl Config {
                     ip: Some(ip.to_string()),
                     _type: Some(_type.to_string()),
                     tunnel_type: Some(tunnel_type.to_string()),
+                    hostname: None,
+                    terminate_tls: None,
+                    udp_idle_timeout: None,
+                    max_udp_flows: None,
+                    tunnel_timeout: None,
+                    connect_timeout: None,
+                    idle_timeout: None,
+                    bandwidth_limit: None,
+                    max_connections: None,
+                    max_connections_per_ip: None,
+                    connection_rate: None,
+                    connection_rate_per_ip: None,
+                    compression: None,
+                    direction: None,
+                    remote_host: None,
+                    service: None,
+                    direct: None,
+                    backends: None,
+                    balance: None,
+                    pool: None,
                 };
 
                 ports.push(port);
@@ -134,6 +290,8 @@ impl Config {
             {
                 "TCP" => PortType::Tcp,
                 "UDP" => PortType::Udp,
+                "HTTP" => PortType::Http,
+                "TLS" => PortType::Tls,
                 _ => {
                     return Err(color_eyre::eyre::eyre!(
                         "Invalid port type: {}",
@@ -151,6 +309,8 @@ impl Config {
             {
                 "TCP" => PortType::Tcp,
                 "UDP" => PortType::Udp,
+                "WS" => PortType::Ws,
+                "QUIC" => PortType::Quic,
                 _ => {
                     return Err(color_eyre::eyre::eyre!(
                         "Invalid port type: {}",
@@ -159,29 +319,251 @@ impl Config {
                 }
             };
 
-            connector_ports.push(ConnectorPort {
+            let compression = match port.compression.as_ref() {
+                Some(compression) => match compression.to_uppercase().as_str() {
+                    "ZSTD" => Some(Compression::Zstd),
+                    "LZ4" => Some(Compression::Lz4),
+                    "NONE" => None,
+                    _ => {
+                        return Err(color_eyre::eyre::eyre!(
+                            "Invalid compression: {}",
+                            compression
+                        ))
+                    }
+                },
+                None => None,
+            };
+
+            let direction = match port
+                .direction
+                .as_ref()
+                .unwrap_or(&String::from("REMOTE"))
+                .to_uppercase()
+                .as_str()
+            {
+                "REMOTE" => Direction::Remote,
+                "LOCAL" => Direction::Local,
+                "DYNAMIC" => Direction::Dynamic,
+                "SERVICE" => Direction::Service,
+                _ => {
+                    return Err(color_eyre::eyre::eyre!(
+                        "Invalid direction: {}",
+                        port.direction.as_ref().unwrap()
+                    ))
+                }
+            };
+
+            let balance = match port
+                .balance
+                .as_ref()
+                .unwrap_or(&String::from("ROUND_ROBIN"))
+                .to_uppercase()
+                .as_str()
+            {
+                "ROUND_ROBIN" => Balance::RoundRobin,
+                "LEAST_CONNECTIONS" => Balance::LeastConnections,
+                "RANDOM" => Balance::Random,
+                _ => {
+                    return Err(color_eyre::eyre::eyre!(
+                        "Invalid balance: {}",
+                        port.balance.as_ref().unwrap()
+                    ))
+                }
+            };
+
+            let connector_port = ConnectorPort {
                 port_remote: port.remote,
                 port_local: port.local,
                 local_ip: port.ip.clone().unwrap_or(String::from("127.0.0.1")),
                 port_type: _type,
                 tunnel_type,
-            });
+                hostname: port.hostname.as_ref().map(|h| h.to_lowercase()),
+                terminate_tls: port.terminate_tls.unwrap_or(false),
+                udp_idle_timeout: port.udp_idle_timeout.unwrap_or(UDP_IDLE_TIMEOUT),
+                max_udp_flows: port.max_udp_flows.unwrap_or(MAX_UDP_FLOWS),
+                tunnel_timeout: port.tunnel_timeout.or(self.tunnel_timeout),
+                connect_timeout: port.connect_timeout.or(self.connect_timeout),
+                tcp_idle_timeout: port.idle_timeout.or(self.idle_timeout),
+                bandwidth_limit: port.bandwidth_limit,
+                max_connections: port.max_connections,
+                max_connections_per_ip: port.max_connections_per_ip,
+                connection_rate: port.connection_rate,
+                connection_rate_per_ip: port.connection_rate_per_ip,
+                compression,
+                direction,
+                remote_host: port.remote_host.clone(),
+                service: port.service.as_ref().map(|s| s.to_lowercase()),
+                direct: port.direct.unwrap_or(false),
+                backends: port.backends.clone().unwrap_or_default(),
+                balance,
+                pool: port.pool.unwrap_or(false),
+            };
+
+            if connector_port.is_host_routed() && connector_port.hostname.is_none() {
+                return Err(color_eyre::eyre::eyre!(
+                    "Missing hostname for {:?} port {}",
+                    connector_port.port_type,
+                    port.remote
+                ));
+            }
+
+            // server reads hostname from tunnels of shared ports only
+            if !connector_port.is_host_routed() && connector_port.hostname.is_some() {
+                return Err(color_eyre::eyre::eyre!(
+                    "Hostname is only supported on HTTP and TLS ports (port {})",
+                    port.remote
+                ));
+            }
+
+            if connector_port.terminate_tls && connector_port.port_type != PortType::Http {
+                return Err(color_eyre::eyre::eyre!(
+                    "TLS termination is only supported on HTTP ports (port {})",
+                    port.remote
+                ));
+            }
+
+            let udp_tunnel = matches!(connector_port.tunnel_type, PortType::Udp | PortType::Quic);
+            if udp_tunnel && self.proxy.is_some() {
+                return Err(color_eyre::eyre::eyre!(
+                    "{:?} tunnel can't be used with proxy (port {})",
+                    connector_port.tunnel_type,
+                    port.remote
+                ));
+            }
+
+            // UDP handshake and QUIC datagrams can't carry noise handshake
+            let datagram_tunnel = connector_port.tunnel_type == PortType::Udp
+                || (connector_port.tunnel_type == PortType::Quic
+                    && connector_port.port_type == PortType::Udp);
+            if datagram_tunnel && self.noise.unwrap_or(false) {
+                return Err(color_eyre::eyre::eyre!(
+                    "Datagram tunnels can't be used with noise (port {})",
+                    port.remote
+                ));
+            }
+
+            if connector_port.tunnel_type == PortType::Quic && self.quic_port.is_none() {
+                return Err(color_eyre::eyre::eyre!(
+                    "QUIC tunnel requires quicPort (port {})",
+                    port.remote
+                ));
+            }
+
+            let uses_service = connector_port.direction == Direction::Service
+                || (connector_port.direction == Direction::Local
+                    && connector_port.service.is_some());
+            if connector_port.direction == Direction::Service && connector_port.service.is_none() {
+                return Err(color_eyre::eyre::eyre!(
+                    "Missing service name (port {})",
+                    port.local
+                ));
+            }
+            if uses_service && self.token.is_none() {
+                return Err(color_eyre::eyre::eyre!(
+                    "Services require token (port {})",
+                    port.local
+                ));
+            }
+            if connector_port.direct && !uses_service {
+                return Err(color_eyre::eyre::eyre!(
+                    "Direct path is only supported for services (port {})",
+                    port.local
+                ));
+            }
+            if connector_port.direct && self.punch_port.is_none() {
+                return Err(color_eyre::eyre::eyre!(
+                    "Direct path requires punchPort (port {})",
+                    port.local
+                ));
+            }
+
+            for backend in connector_port.backends.iter() {
+                let valid = backend
+                    .rsplit_once(':')
+                    .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+                if !valid {
+                    return Err(color_eyre::eyre::eyre!("Invalid backend: {}", backend));
+                }
+            }
+
+            // only connections client makes to local services can be balanced
+            let dials_local = matches!(
+                connector_port.direction,
+                Direction::Remote | Direction::Service
+            );
+            let balanced = !connector_port.backends.is_empty();
+            if balanced && (!dials_local || connector_port.port_type == PortType::Udp) {
+                return Err(color_eyre::eyre::eyre!(
+                    "Backends are only supported on remote and service TCP ports (port {})",
+                    port.remote
+                ));
+            }
+
+            if connector_port.pool && !dials_local {
+                return Err(color_eyre::eyre::eyre!(
+                    "Pool is only supported on remote and service ports (port {})",
+                    port.local
+                ));
+            }
+
+            // client listens on TCP, forwards need stream tunnels to carry the request
+            let forward = connector_port.direction != Direction::Remote;
+            if forward && connector_port.port_type != PortType::Tcp {
+                return Err(color_eyre::eyre::eyre!(
+                    "{:?} forward only supports TCP ports (port {})",
+                    connector_port.direction,
+                    port.local
+                ));
+            }
+            if forward && connector_port.tunnel_type == PortType::Udp {
+                return Err(color_eyre::eyre::eyre!(
+                    "{:?} forward can't use UDP tunnel (port {})",
+                    connector_port.direction,
+                    port.local
+                ));
+            }
+
+            connector_ports.push(connector_port);
         }
 
-        let connector_splitted = self.connector.split(":").collect::<Vec<&str>>();
+        let (transport, connector) = match self.connector.strip_prefix("ws://") {
+            Some(connector) => (PortType::Ws, connector),
+            None => (PortType::Tcp, self.connector.as_str()),
+        };
+
+        let connector_splitted = connector
+            .trim_end_matches('/')
+            .split(":")
+            .collect::<Vec<&str>>();
         let connector_ip = connector_splitted[0].to_string();
         let connector_port = connector_splitted
             .get(1)
             .unwrap_or(&"1337")
             .parse::<u16>()?;
 
+        let quic_fingerprint = match self.quic_fingerprint.as_deref() {
+            Some(fingerprint) => Some(quic::parse_fingerprint(fingerprint).ok_or_else(|| {
+                color_eyre::eyre::eyre!("Invalid QUIC fingerprint: {}", fingerprint)
+            })?),
+            None => None,
+        };
+
         let converted_config = ConvertedConfig {
             connector: ConnectorInfo {
                 ports: connector_ports,
+                token: self.token.clone(),
             },
             code: self.code,
-            connector_ip,
-            connector_port,
+            connector_addr: ConnectorAddr {
+                ip: connector_ip,
+                port: connector_port,
+                proxy: self.proxy.as_deref().map(Proxy::parse).transpose()?,
+                quic_port: self.quic_port,
+                quic_fingerprint,
+                punch_port: self.punch_port,
+                noise: self.noise.unwrap_or(false),
+            },
+            transport,
         };
 
         Ok(converted_config)
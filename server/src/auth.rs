@@ -0,0 +1,115 @@
+use crate::structs::Config;
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub const HANDSHAKE_TIMEOUT: u64 = 10;
+pub const MAX_AUTH_FAILURES: u32 = 5;
+pub const BAN_DURATION: u64 = 600;
+
+/// Number of tracked addresses after which forgotten ones are pruned
+const PRUNE_THRESHOLD: usize = 1024;
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    banned_until: Option<Instant>,
+}
+
+lazy_static! {
+    /// Failed connector code attempts per source address
+    static ref FAILURES: Mutex<HashMap<IpAddr, Failures>> = Mutex::new(HashMap::new());
+}
+
+pub fn handshake_timeout(config: &Config) -> Duration {
+    Duration::from_secs(config.handshake_timeout.unwrap_or(HANDSHAKE_TIMEOUT))
+}
+
+fn ban_duration(config: &Config) -> Duration {
+    Duration::from_secs(config.ban_duration.unwrap_or(BAN_DURATION))
+}
+
+pub fn is_banned(ip: IpAddr) -> bool {
+    let failures = match FAILURES.lock() {
+        Ok(failures) => failures,
+        Err(_) => return false,
+    };
+
+    failures
+        .get(&ip)
+        .and_then(|f| f.banned_until)
+        .is_some_and(|until| until > Instant::now())
+}
+
+/// Counts wrong connector code, address gets banned after too many of them.
+/// Failures are forgotten after ban duration without new one.
+pub fn record_failure(ip: IpAddr, config: &Config) {
+    let mut failures = match FAILURES.lock() {
+        Ok(failures) => failures,
+        Err(_) => return,
+    };
+
+    let ban_duration = ban_duration(config);
+    if failures.len() >= PRUNE_THRESHOLD {
+        failures.retain(|_, f| {
+            f.last.elapsed() < ban_duration && f.banned_until.is_none_or(|u| u > Instant::now())
+        });
+    }
+
+    let now = Instant::now();
+    let entry = failures.entry(ip).or_insert(Failures {
+        count: 0,
+        last: now,
+        banned_until: None,
+    });
+
+    if now.duration_since(entry.last) >= ban_duration {
+        entry.count = 0;
+    }
+    entry.count += 1;
+    entry.last = now;
+
+    let max_failures = config.max_auth_failures.unwrap_or(MAX_AUTH_FAILURES);
+    eprintln!(
+        "Wrong connector code from {} ({}/{})",
+        ip, entry.count, max_failures
+    );
+
+    if entry.count >= max_failures {
+        entry.count = 0;
+        entry.banned_until = Some(now + ban_duration);
+        eprintln!("Banned {} for {:?}", ip, ban_duration);
+    }
+}
+
+pub fn record_success(ip: IpAddr) {
+    if let Ok(mut failures) = FAILURES.lock() {
+        failures.remove(&ip);
+    }
+}
+
+pub enum Permission {
+    Expose,
+    Consume,
+}
+
+/// Checks that `token` grants the permission for the service, services are
+/// disabled without configured tokens
+pub fn is_permitted(config: &Config, token: &str, service: &str, permission: Permission) -> bool {
+    let permissions = match config.tokens.as_ref().and_then(|tokens| tokens.get(token)) {
+        Some(permissions) => permissions,
+        None => return false,
+    };
+
+    let services = match permission {
+        Permission::Expose => &permissions.expose,
+        Permission::Consume => &permissions.consume,
+    };
+    services
+        .iter()
+        .any(|s| s == "*" || s.eq_ignore_ascii_case(service))
+}
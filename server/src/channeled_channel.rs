@@ -1,27 +1,30 @@
 use color_eyre::Result;
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, hash::Hash, sync::Arc};
 use tokio::sync::RwLock;
 
-pub struct ChanneledChannel<T> {
-    channels: Arc<RwLock<HashMap<u16, (async_channel::Sender<T>, async_channel::Receiver<T>)>>>,
+pub struct ChanneledChannel<K, T> {
+    channels: Arc<RwLock<HashMap<K, (async_channel::Sender<T>, async_channel::Receiver<T>)>>>,
 }
 
 #[allow(dead_code)]
-impl<T> ChanneledChannel<T> {
+impl<K, T> ChanneledChannel<K, T>
+where
+    K: Eq + Hash + Clone,
+{
     pub fn new() -> Self {
         Self {
             channels: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
-    pub async fn create_channel(&self, id: &u16) -> Result<()> {
+    pub async fn create_channel(&self, id: &K) -> Result<()> {
         let (tx, rx) = async_channel::unbounded::<T>();
-        self.channels.write().await.insert(*id, (tx, rx));
+        self.channels.write().await.insert(id.clone(), (tx, rx));
 
         Ok(())
     }
 
-    pub async fn remove_channel(&self, id: &u16) -> Result<()> {
+    pub async fn remove_channel(&self, id: &K) -> Result<()> {
         self.channels.write().await.remove(id);
 
         Ok(())
@@ -33,12 +36,12 @@ impl<T> ChanneledChannel<T> {
         Ok(())
     }
 
-    pub async fn get_sender(&self, id: &u16) -> Option<async_channel::Sender<T>> {
+    pub async fn get_sender(&self, id: &K) -> Option<async_channel::Sender<T>> {
         let channels = self.channels.read().await;
         channels.get(id).map(|(tx, _)| tx.clone())
     }
 
-    pub async fn get_receiver(&self, id: &u16) -> Option<async_channel::Receiver<T>> {
+    pub async fn get_receiver(&self, id: &K) -> Option<async_channel::Receiver<T>> {
         let channels = self.channels.read().await;
         channels.get(id).map(|(_, rx)| rx.clone())
     }
@@ -1,37 +1,76 @@
 use crate::{
-    channeled_channel,
-    structs::Config,
+    auth::{self, Permission},
+    forward,
+    metrics::{self, Reason},
+    punch, service,
+    structs::{Config, TunnelKey},
+    tls,
     tunnel::{self, BUFFER_SIZE},
-    ConnectorChannel,
+    ConnectorChannel, TunnelChannels,
 };
 use color_eyre::Result;
-use std::sync::Arc;
+use std::net::SocketAddr;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpListener,
-    sync::RwLock,
-    task::JoinHandle,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
 };
 use udpflow::{UdpListener, UdpSocket};
-use utils::{ConnectorInfo, MultiStream};
+use utils::{
+    bandwidth::Limits,
+    compression::Compression,
+    framing::UdpHandshake,
+    noise,
+    quic::{self, Identity, QuicPeer},
+    websocket, ConnectorInfo, Direction, MultiStream, FORWARD_PUNCH, FORWARD_SERVICE, FORWARD_TCP,
+    FORWARD_UDP, PUNCH_LISTEN, SERVICE_TUNNEL,
+};
 
-pub async fn spawn_connector_worker(
-    connector_channel: ConnectorChannel,
-    tunnel_channels: channeled_channel::ChanneledChannel<MultiStream>,
-    config: Config,
-) -> Result<()> {
+pub async fn spawn_connector_worker(tunnel_channels: TunnelChannels, config: Config) -> Result<()> {
     let tunnel_channels_cp = tunnel_channels.clone();
     let config_cp = config.clone();
 
+    // shared by every session, so whole server stays under the limit
+    let limits = Limits::default().with(metrics::bucket(
+        "scope=\"server\"".to_string(),
+        config.bandwidth_limit,
+    ));
+
+    let limits_cp = limits.clone();
     tokio::spawn(async move {
         loop {
-            if let Err(e) = connector_worker(&connector_channel, &tunnel_channels, &config).await {
+            if let Err(e) = connector_worker(&tunnel_channels, &config, &limits_cp).await {
                 eprintln!("Connection worker error: {:?}", e);
                 tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
             }
         }
     });
 
+    if let Some(quic_port) = config_cp.quic_port {
+        let identity = tls::quic_identity()?;
+        println!("QUIC certificate fingerprint: {}", identity.fingerprint());
+
+        let tunnel_channels = tunnel_channels_cp.clone();
+        let config = config_cp.clone();
+        let limits = limits.clone();
+
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) =
+                    connector_worker_quic(&tunnel_channels, &config, &limits, quic_port, &identity)
+                        .await
+                {
+                    eprintln!("QUIC listener error: {:?}", e);
+                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                }
+            }
+        });
+    }
+
+    // UDP handshake sends the code as is, so it's disabled with noise
+    if config_cp.noise.unwrap_or(false) {
+        return Ok(());
+    }
+
     tokio::spawn(async move {
         loop {
             if let Err(e) = connector_worker_udp(&tunnel_channels_cp, &config_cp).await {
@@ -45,70 +84,98 @@ pub async fn spawn_connector_worker(
 }
 
 async fn connector_worker(
-    connector_channel: &ConnectorChannel,
-    tunnel_channels: &channeled_channel::ChanneledChannel<MultiStream>,
+    tunnel_channels: &TunnelChannels,
     config: &Config,
+    limits: &Limits,
 ) -> Result<()> {
     let listener = TcpListener::bind(format!("0.0.0.0:{}", config.port)).await?;
-    let connector_task: Arc<RwLock<Option<JoinHandle<()>>>> = Arc::new(RwLock::new(None));
 
     loop {
-        let (mut socket, _) = listener.accept().await?;
+        let (socket, addr) = listener.accept().await?;
+        if auth::is_banned(addr.ip()) {
+            continue;
+        }
         socket.set_nodelay(true)?;
 
         let tunnel_channels = tunnel_channels.clone();
-        let connector_task = connector_task.clone();
-        let connector_channel = connector_channel.clone();
         let config = config.clone();
+        let limits = limits.clone();
 
         tokio::spawn(async move {
-            let mut buf = [0; 10];
-            socket.read_exact(&mut buf).await?;
+            let preamble = read_preamble(socket, &config);
+            let auth = match tokio::time::timeout(auth::handshake_timeout(&config), preamble).await
+            {
+                Ok(res) => res?,
+                Err(_) => {
+                    eprintln!("Handshake timed out ({})", addr);
+                    metrics::record(Reason::HandshakeTimeout);
+                    return Ok(());
+                }
+            };
 
-            let port = u16::from_be_bytes(buf[0..2].try_into()?);
-            let code = u64::from_be_bytes(buf[2..10].try_into()?);
-            if code != config.code {
-                return Ok(());
-            }
+            let (mut socket, port) = match auth {
+                Some(auth) => auth,
+                None => {
+                    auth::record_failure(addr.ip(), &config);
+                    metrics::record(Reason::AuthFailure);
+                    return Ok(());
+                }
+            };
+            auth::record_success(addr.ip());
 
             if port == 0 {
-                if let Some(task) = connector_task.read().await.as_ref() {
-                    task.abort();
+                let info_len = socket.read_u16().await?;
+                if info_len == 0 {
+                    return serve_forward(socket, &config, &limits, &tunnel_channels, addr).await;
                 }
 
-                let info_len = socket.read_u16().await?;
                 let mut info = vec![0; info_len as usize];
                 socket.read_exact(&mut info).await?;
 
-                let info: ConnectorInfo = ConnectorInfo::decode(&info)?;
+                let mut info: ConnectorInfo = ConnectorInfo::decode(&info)?;
+                for port in info.ports.iter_mut() {
+                    port.tunnel_timeout = port.tunnel_timeout.or(config.tunnel_timeout);
+                }
+
+                // services can be exposed only with permission of the client's token
+                let token = info.token.clone().unwrap_or_default();
+                info.ports.retain(|port| {
+                    if port.direction != Direction::Service {
+                        return true;
+                    }
+
+                    let name = port.service.as_deref().unwrap_or_default();
+                    let permitted = auth::is_permitted(&config, &token, name, Permission::Expose);
+                    if !permitted {
+                        eprintln!("{} isn't allowed to expose service {}", addr, name);
+                    }
+                    permitted
+                });
+                let session = rand::random::<u64>();
+                let connector_channel = async_channel::unbounded::<(TunnelKey, u64)>();
+                let limits = limits.with(metrics::bucket(
+                    format!("scope=\"session\",session=\"{:016x}\"", session),
+                    config.session_bandwidth_limit,
+                ));
+
                 tunnel::spawn_multiple_tunnels(
                     tunnel_channels.clone(),
                     connector_channel.clone(),
+                    session,
+                    limits,
                     info.ports,
                 )
                 .await?;
 
-                let task = tokio::spawn(async move {
-                    while let Ok(port) = connector_channel.1.recv().await {
-                        if let Err(e) = socket.write_u16(port).await {
-                            eprintln!("Failed to write to socket {:?}", e);
-                            //let _ = channel.0.send(port);
-
-                            return;
-                        }
-                    }
-                });
+                if let Err(e) = session_worker(&mut socket, &connector_channel).await {
+                    eprintln!("Session error: {:?}", e);
+                }
 
-                connector_task.write().await.replace(task);
+                tunnel::remove_session(session).await?;
             } else {
-                tunnel_channels
-                    .get_sender(&port)
-                    .await
-                    .ok_or_else(|| {
-                        color_eyre::eyre::eyre!("Could not get sender for port {}", port)
-                    })?
-                    .send(MultiStream::Tcp(socket))
-                    .await?;
+                let request = read_tunnel_request(&mut socket, port).await?;
+                let socket = read_compression(&request.0, socket).await?;
+                send_tunnel(&tunnel_channels, &request, socket).await?;
             }
 
             Ok::<(), color_eyre::Report>(())
@@ -116,38 +183,279 @@ async fn connector_worker(
     }
 }
 
-async fn connector_worker_udp(
-    tunnel_channels: &channeled_channel::ChanneledChannel<MultiStream>,
+/// Port 0 connections with empty client info are forwards, their kind tells
+/// which one
+async fn serve_forward(
+    mut socket: MultiStream,
+    config: &Config,
+    limits: &Limits,
+    tunnel_channels: &TunnelChannels,
+    addr: SocketAddr,
+) -> Result<()> {
+    match socket.read_u8().await? {
+        FORWARD_TCP => forward::dial_tcp(socket, config, limits).await,
+        FORWARD_UDP => forward::relay_udp(socket, config, limits).await,
+        FORWARD_SERVICE => service::connect(socket, config, tunnel_channels, addr).await,
+        SERVICE_TUNNEL => match service::read_tunnel(socket, config, addr).await? {
+            Some((request, socket)) => send_tunnel(tunnel_channels, &request, socket).await,
+            None => Ok(()),
+        },
+        FORWARD_PUNCH => punch::connect(socket, config, addr).await,
+        PUNCH_LISTEN => punch::listen(socket, config, addr).await,
+        kind => Err(color_eyre::eyre::eyre!("Invalid forward kind: {}", kind)),
+    }
+}
+
+/// Accepts WebSocket upgrade (if it's one) and authenticates the connection
+async fn read_preamble(socket: TcpStream, config: &Config) -> Result<Option<(MultiStream, u16)>> {
+    let code = (!config.noise.unwrap_or(false)).then_some(config.code);
+    let socket = if websocket::is_upgrade(&socket, code).await? {
+        MultiStream::Ws(Box::new(websocket::accept(socket).await?))
+    } else {
+        MultiStream::Tcp(socket)
+    };
+
+    authenticate(socket, config).await
+}
+
+/// Checks connector code and returns the connection with its requested port,
+/// `None` means wrong code. With noise the code is only the handshake key and
+/// returned connection is encrypted.
+async fn authenticate(
+    mut socket: MultiStream,
     config: &Config,
+) -> Result<Option<(MultiStream, u16)>> {
+    if config.noise.unwrap_or(false) {
+        let mut socket = match noise::respond(socket, config.code).await? {
+            Some(stream) => MultiStream::Noise(Box::new(stream)),
+            None => return Ok(None),
+        };
+
+        let port = socket.read_u16().await?;
+        return Ok(Some((socket, port)));
+    }
+
+    let port = socket.read_u16().await?;
+    let code = socket.read_u64().await?;
+
+    Ok((code == config.code).then_some((socket, port)))
+}
+
+/// Reads rest of the tunnel preamble, hostname of shared ports (empty for
+/// other ones) and id of the request the tunnel answers
+async fn read_tunnel_request<T>(socket: &mut T, port: u16) -> Result<(TunnelKey, u64)>
+where
+    T: AsyncRead + Unpin,
+{
+    let hostname = utils::read_hostname(socket).await?.to_lowercase();
+    let request_id = socket.read_u64().await?;
+
+    let key = TunnelKey {
+        port,
+        hostname: (!hostname.is_empty()).then_some(hostname),
+    };
+    Ok((key, request_id))
+}
+
+/// Tunnels of ports with compression end their preamble with id of the
+/// algorithm, client picks it so the server just follows
+async fn read_compression(key: &TunnelKey, mut socket: MultiStream) -> Result<MultiStream> {
+    let route = tunnel::get_route(key).await;
+    if route.and_then(|r| r.port.compression()).is_none() {
+        return Ok(socket);
+    }
+
+    let compression = Compression::from_id(socket.read_u8().await?)?;
+    Ok(socket.compressed(Some(compression)))
+}
+
+/// Hands tunnel to the connection which requested it, tunnels of requests
+/// which were already given up on are dropped
+async fn send_tunnel(
+    tunnel_channels: &TunnelChannels,
+    request: &(TunnelKey, u64),
+    socket: MultiStream,
+) -> Result<()> {
+    match tunnel_channels.get_sender(request).await {
+        Some(sender) => sender.send(socket).await?,
+        None => eprintln!(
+            "Tunnel for {} came too late, dropping it",
+            tunnel::describe(&request.0)
+        ),
+    }
+
+    Ok(())
+}
+
+/// Forwards tunnel requests to the client until its control connection closes
+async fn session_worker(
+    socket: &mut MultiStream,
+    connector_channel: &ConnectorChannel,
 ) -> Result<()> {
+    let mut buf = [0; 1];
+
+    loop {
+        tokio::select! {
+            Ok((key, request_id)) = connector_channel.1.recv() => {
+                socket.write_u16(key.port).await?;
+                utils::write_hostname(socket, key.hostname.as_deref().unwrap_or_default()).await?;
+                socket.write_u64(request_id).await?;
+                socket.flush().await?;
+            }
+            res = socket.read(&mut buf) => {
+                if res? == 0 {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+async fn connector_worker_udp(tunnel_channels: &TunnelChannels, config: &Config) -> Result<()> {
     let socket = UdpSocket::bind(format!("0.0.0.0:{}", config.port)).await?;
     let listener = UdpListener::new(socket);
 
-    let buf = &mut [0; BUFFER_SIZE];
+    let buf = &mut vec![0; BUFFER_SIZE];
     loop {
-        let (mut socket, _) = listener.accept(&mut buf[..]).await?;
+        let (mut socket, addr) = listener.accept(&mut buf[..]).await?;
+        if auth::is_banned(addr.ip()) {
+            continue;
+        }
 
         let tunnel_channels = tunnel_channels.clone();
         let config = config.clone();
 
         tokio::spawn(async move {
-            let mut buf = [0; 18];
-            socket.read_exact(&mut buf).await?;
+            let handshake = match tokio::time::timeout(
+                auth::handshake_timeout(&config),
+                UdpHandshake::receive(&mut socket),
+            )
+            .await
+            {
+                Ok(handshake) => handshake?,
+                Err(_) => {
+                    eprintln!("Handshake timed out ({})", addr);
+                    metrics::record(Reason::HandshakeTimeout);
+                    return Ok(());
+                }
+            };
 
-            let port = u16::from_be_bytes(buf[0..2].try_into()?);
-            let code = u64::from_be_bytes(buf[2..10].try_into()?);
-            if code != config.code {
+            if handshake.code != config.code {
+                auth::record_failure(addr.ip(), &config);
+                metrics::record(Reason::AuthFailure);
                 return Ok(());
             }
+            if handshake.port == 0 {
+                return Ok(());
+            }
+            auth::record_success(addr.ip());
+            let socket = handshake.accept(socket).await?;
 
-            tunnel_channels
-                .get_sender(&port)
-                .await
-                .ok_or_else(|| color_eyre::eyre::eyre!("Could not get sender for port {}", port))?
-                .send(MultiStream::UdpLocal(socket))
-                .await?;
+            let key = TunnelKey {
+                port: handshake.port,
+                hostname: handshake.hostname.map(|h| h.to_lowercase()),
+            };
+
+            let request = (key, handshake.request_id);
+            send_tunnel(&tunnel_channels, &request, MultiStream::UdpLocal(socket)).await?;
+
+            Ok::<(), color_eyre::Report>(())
+        });
+    }
+}
+
+async fn connector_worker_quic(
+    tunnel_channels: &TunnelChannels,
+    config: &Config,
+    limits: &Limits,
+    quic_port: u16,
+    identity: &Identity,
+) -> Result<()> {
+    let endpoint = quic::server_endpoint(quic_port, identity)?;
+
+    while let Some(connecting) = endpoint.accept().await {
+        let addr = connecting.remote_address();
+        if auth::is_banned(addr.ip()) {
+            continue;
+        }
+
+        let tunnel_channels = tunnel_channels.clone();
+        let config = config.clone();
+        let limits = limits.clone();
+
+        tokio::spawn(async move {
+            let peer = QuicPeer::new(connecting.await?);
+
+            while let Ok(mut stream) = peer.accept_bi().await {
+                let tunnel_channels = tunnel_channels.clone();
+                let config = config.clone();
+                let limits = limits.clone();
+                let peer = peer.clone();
+
+                tokio::spawn(async move {
+                    let preamble = async {
+                        let kind = stream.read_u8().await?;
+                        if kind == quic::DATAGRAM_TUNNEL && config.noise.unwrap_or(false) {
+                            color_eyre::eyre::bail!(
+                                "QUIC datagram tunnel can't be used with noise"
+                            );
+                        }
+
+                        let auth =
+                            authenticate(MultiStream::Quic(Box::new(stream)), &config).await?;
+                        Ok((kind, auth))
+                    };
+
+                    let (kind, auth) = match tokio::time::timeout(
+                        auth::handshake_timeout(&config),
+                        preamble,
+                    )
+                    .await
+                    {
+                        Ok(res) => res?,
+                        Err(_) => {
+                            eprintln!("Handshake timed out ({})", addr);
+                            metrics::record(Reason::HandshakeTimeout);
+                            return Ok(());
+                        }
+                    };
+
+                    let (mut socket, port) = match auth {
+                        Some(auth) => auth,
+                        None => {
+                            auth::record_failure(addr.ip(), &config);
+                            metrics::record(Reason::AuthFailure);
+                            return Ok(());
+                        }
+                    };
+                    auth::record_success(addr.ip());
+
+                    // only local forwards use port 0 over QUIC, control connection stays on TCP
+                    if port == 0 {
+                        if socket.read_u16().await? == 0 {
+                            serve_forward(socket, &config, &limits, &tunnel_channels, addr).await?;
+                        }
+                        return Ok(());
+                    }
+
+                    let request = read_tunnel_request(&mut socket, port).await?;
+                    let socket = match socket {
+                        MultiStream::Quic(stream) if kind == quic::DATAGRAM_TUNNEL => {
+                            let mut flow = peer.register_flow(peer.next_flow_id(), *stream);
+                            flow.announce().await?;
+
+                            MultiStream::QuicDatagram(Box::new(flow))
+                        }
+                        socket => read_compression(&request.0, socket).await?,
+                    };
+
+                    send_tunnel(&tunnel_channels, &request, socket).await
+                });
+            }
 
             Ok::<(), color_eyre::Report>(())
         });
     }
+
+    Ok(())
 }
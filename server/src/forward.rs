@@ -0,0 +1,242 @@
+use crate::structs::Config;
+use color_eyre::Result;
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+use utils::{
+    bandwidth::Limits,
+    compression::Compression,
+    socks::{self, Address},
+    CopyOptions, MultiStream, PortType, BUFFER_SIZE, CONNECT_TIMEOUT,
+};
+
+/// Resolved destinations remembered by one UDP relay, forgotten all at once
+/// when there's more of them
+const MAX_UDP_TARGETS: usize = 1024;
+
+/// Connects local forward tunnel to its target, the client gets SOCKS reply
+/// code before any forwarded data
+pub async fn dial_tcp(mut socket: MultiStream, config: &Config, limits: &Limits) -> Result<()> {
+    let compression = read_compression(&mut socket).await?;
+    let host = utils::read_hostname(&mut socket).await?.to_lowercase();
+    let port = socket.read_u16().await?;
+
+    let addr = match resolve(config, &host, port).await {
+        Ok(Some(addr)) => addr,
+        Ok(None) => {
+            eprintln!("Forward to {}:{} not allowed", host, port);
+            return reply(socket, socks::NOT_ALLOWED).await;
+        }
+        Err(e) => {
+            eprintln!("Can't resolve {}:{}: {}", host, port, e);
+            return reply(socket, socks::HOST_UNREACHABLE).await;
+        }
+    };
+
+    let connect = TcpStream::connect(addr);
+    let target = match tokio::time::timeout(Duration::from_secs(CONNECT_TIMEOUT), connect).await {
+        Ok(Ok(target)) => target,
+        Ok(Err(e)) => {
+            eprintln!("Forward connect failed ({}:{}): {}", host, port, e);
+            let status = match e.kind() {
+                ErrorKind::ConnectionRefused => socks::CONNECTION_REFUSED,
+                _ => socks::HOST_UNREACHABLE,
+            };
+            return reply(socket, status).await;
+        }
+        Err(_) => {
+            eprintln!("Forward connect timed out ({}:{})", host, port);
+            return reply(socket, socks::HOST_UNREACHABLE).await;
+        }
+    };
+    target.set_nodelay(true)?;
+
+    socket.write_u8(socks::SUCCEEDED).await?;
+    socket.flush().await?;
+
+    // idle timeout is up to the client, closing its side closes this one too
+    socket
+        .compressed(compression)
+        .copy_bidirectional_tcp(
+            target,
+            &CopyOptions {
+                limits: limits.clone(),
+                ..Default::default()
+            },
+        )
+        .await
+}
+
+/// Compression id of the forward, 0 is none
+pub async fn read_compression(socket: &mut MultiStream) -> Result<Option<Compression>> {
+    match socket.read_u8().await? {
+        0 => Ok(None),
+        id => Ok(Some(Compression::from_id(id)?)),
+    }
+}
+
+/// Answers forward which isn't going to be opened
+pub async fn reply(mut socket: MultiStream, status: u8) -> Result<()> {
+    socket.write_u8(status).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+/// Relays SOCKS UDP datagrams (with their header) between the tunnel and
+/// their destinations, every destination is checked by the policy. Only
+/// addresses something was sent to can answer.
+pub async fn relay_udp(mut socket: MultiStream, config: &Config, limits: &Limits) -> Result<()> {
+    let udp = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.write_u8(socks::SUCCEEDED).await?;
+    socket.flush().await?;
+
+    let mut tunnel = socket.framed(&PortType::Udp);
+    let mut targets: HashMap<Address, Option<SocketAddr>> = HashMap::new();
+    let mut peers: HashSet<SocketAddr> = HashSet::new();
+
+    let tunnel_buf = &mut vec![0; BUFFER_SIZE];
+    let udp_buf = &mut vec![0; BUFFER_SIZE];
+    loop {
+        tokio::select! {
+            res = tunnel.read(&mut tunnel_buf[..]) => {
+                let n = res?;
+                if n == 0 {
+                    return Ok(());
+                }
+
+                // fragments aren't supported, like in most SOCKS servers
+                let datagram = &tunnel_buf[..n];
+                if n < socks::UDP_HEADER_SIZE || datagram[2] != 0 {
+                    continue;
+                }
+                let (address, len) = match Address::decode(&datagram[socks::UDP_HEADER_SIZE..]) {
+                    Ok(address) => address,
+                    Err(_) => continue,
+                };
+
+                if !targets.contains_key(&address) {
+                    if targets.len() >= MAX_UDP_TARGETS {
+                        targets.clear();
+                        peers.clear();
+                    }
+
+                    let target = match resolve(config, &address.host(), address.port()).await {
+                        Ok(None) => {
+                            eprintln!("UDP forward to {} not allowed", address);
+                            None
+                        }
+                        Ok(target) => target,
+                        Err(e) => {
+                            eprintln!("Can't resolve {}: {}", address, e);
+                            None
+                        }
+                    };
+                    targets.insert(address.clone(), target);
+                }
+
+                if let Some(target) = targets[&address] {
+                    let payload = &datagram[socks::UDP_HEADER_SIZE + len..];
+                    limits.consume(payload.len()).await;
+
+                    peers.insert(target);
+                    if let Err(e) = udp.send_to(payload, target).await {
+                        eprintln!("UDP forward to {} failed: {}", target, e);
+                    }
+                }
+            }
+            res = udp.recv_from(&mut udp_buf[..]) => {
+                let (n, addr) = res?;
+                if !peers.contains(&addr) {
+                    continue;
+                }
+                limits.consume(n).await;
+
+                let mut datagram = vec![0; socks::UDP_HEADER_SIZE];
+                Address::Ip(addr).encode(&mut datagram)?;
+                datagram.extend_from_slice(&udp_buf[..n]);
+
+                tunnel.write_all(&datagram).await?;
+                tunnel.flush().await?;
+            }
+        }
+    }
+}
+
+/// Resolves the target and returns its first address allowed by the policy,
+/// so names pointing to denied addresses can't get around it
+async fn resolve(config: &Config, host: &str, port: u16) -> Result<Option<SocketAddr>> {
+    if config.allowed_targets.is_none() {
+        return Ok(None);
+    }
+
+    let addr = tokio::net::lookup_host((host, port))
+        .await?
+        .find(|addr| is_allowed(config, host, addr));
+    Ok(addr)
+}
+
+/// Allowed targets have to match `allowed_targets` and none of
+/// `denied_targets`, nothing is allowed without them
+fn is_allowed(config: &Config, host: &str, addr: &SocketAddr) -> bool {
+    let allowed = config
+        .allowed_targets
+        .iter()
+        .flatten()
+        .any(|pattern| matches(pattern, host, addr));
+    let denied = config
+        .denied_targets
+        .iter()
+        .flatten()
+        .any(|pattern| matches(pattern, host, addr));
+
+    allowed && !denied
+}
+
+/// Patterns are `host:port`, host can be a name, IP address, CIDR range or
+/// `*` and port a number or `*`
+fn matches(pattern: &str, host: &str, addr: &SocketAddr) -> bool {
+    let (pattern_host, pattern_port) = match pattern.rsplit_once(':') {
+        Some(pattern) => pattern,
+        None => return false,
+    };
+    if pattern_port != "*" && pattern_port.parse() != Ok(addr.port()) {
+        return false;
+    }
+
+    let pattern_host = pattern_host.trim_start_matches('[').trim_end_matches(']');
+    if pattern_host == "*" || pattern_host.eq_ignore_ascii_case(host) {
+        return true;
+    }
+
+    let ip = addr.ip().to_canonical();
+    match pattern_host.split_once('/') {
+        Some((network, prefix)) => in_network(ip, network, prefix),
+        None => pattern_host.parse() == Ok(ip),
+    }
+}
+
+fn in_network(ip: IpAddr, network: &str, prefix: &str) -> bool {
+    let prefix: u32 = match prefix.parse() {
+        Ok(prefix) => prefix,
+        Err(_) => return false,
+    };
+
+    match (ip, network.parse()) {
+        (IpAddr::V4(ip), Ok(IpAddr::V4(network))) if prefix <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), Ok(IpAddr::V6(network))) if prefix <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,126 @@
+use color_eyre::{eyre::bail, Result};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use utils::{ConnectorPort, PortType};
+
+/// Limits new and concurrent connections of one forwarded port, so port scans
+/// and floods are dropped on the server instead of turning into dial-backs
+pub struct ConnectionLimiter {
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    rate: Option<u32>,
+    rate_per_ip: Option<u32>,
+    state: Mutex<LimiterState>,
+}
+
+#[derive(Default)]
+struct LimiterState {
+    active: usize,
+    active_per_ip: HashMap<IpAddr, usize>,
+
+    /// New connections are counted in one second windows
+    window: Option<Instant>,
+    new: u32,
+    new_per_ip: HashMap<IpAddr, u32>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(port: &ConnectorPort) -> Arc<Self> {
+        // UDP flows are connections too, whichever cap is lower wins
+        let max_connections = match port.port_type {
+            PortType::Udp => Some(
+                port.max_connections
+                    .map_or(port.max_udp_flows, |max| max.min(port.max_udp_flows)),
+            ),
+            _ => port.max_connections,
+        };
+
+        Arc::new(Self {
+            max_connections,
+            max_connections_per_ip: port.max_connections_per_ip,
+            rate: port.connection_rate,
+            rate_per_ip: port.connection_rate_per_ip,
+            state: Mutex::new(LimiterState::default()),
+        })
+    }
+
+    /// Reserves slot for new connection from `ip` (freed when guard is
+    /// dropped), fails if it would go over any limit
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionGuard> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => bail!("Limiter poisoned"),
+        };
+
+        if state
+            .window
+            .is_none_or(|w| w.elapsed() >= Duration::from_secs(1))
+        {
+            state.window = Some(Instant::now());
+            state.new = 0;
+            state.new_per_ip.clear();
+        }
+
+        if self.max_connections.is_some_and(|max| state.active >= max) {
+            bail!("too many connections");
+        }
+
+        let active_ip = state.active_per_ip.get(&ip).copied().unwrap_or(0);
+        if self
+            .max_connections_per_ip
+            .is_some_and(|max| active_ip >= max)
+        {
+            bail!("too many connections from one address");
+        }
+
+        if self.rate.is_some_and(|rate| state.new >= rate) {
+            bail!("too many new connections per second");
+        }
+
+        let new_ip = state.new_per_ip.get(&ip).copied().unwrap_or(0);
+        if self.rate_per_ip.is_some_and(|rate| new_ip >= rate) {
+            bail!("too many new connections per second from one address");
+        }
+
+        state.active += 1;
+        state.new += 1;
+        *state.active_per_ip.entry(ip).or_default() += 1;
+        *state.new_per_ip.entry(ip).or_default() += 1;
+
+        Ok(ConnectionGuard {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    fn release(&self, ip: IpAddr) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+
+        state.active = state.active.saturating_sub(1);
+        if let Some(active) = state.active_per_ip.get_mut(&ip) {
+            *active -= 1;
+            if *active == 0 {
+                state.active_per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+/// Slot of one open connection
+pub struct ConnectionGuard {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
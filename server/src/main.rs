@@ -1,22 +1,47 @@
-use crate::structs::Config;
+use crate::structs::{Config, TunnelKey};
 use color_eyre::Result;
+use utils::MultiStream;
 
+mod auth;
 mod channeled_channel;
 mod connector_worker;
+mod forward;
+mod limiter;
+mod metrics;
+mod punch;
+mod service;
 mod structs;
+mod tls;
 mod tunnel;
+mod vhost;
 
-pub type ConnectorChannel = (async_channel::Sender<u16>, async_channel::Receiver<u16>);
+/// Tunnel requests for the client's session, with id the tunnel answers with
+pub type ConnectorChannel = (
+    async_channel::Sender<(TunnelKey, u64)>,
+    async_channel::Receiver<(TunnelKey, u64)>,
+);
+/// Tunnels dialed back by clients, one channel per waiting request
+pub type TunnelChannels = channeled_channel::ChanneledChannel<(TunnelKey, u64), MultiStream>;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
-    let tunnel_channels = channeled_channel::ChanneledChannel::new();
-    let connector_channel = async_channel::unbounded::<u16>();
+    let tunnel_channels = TunnelChannels::new();
     let config = Config::load().await?;
 
     println!("Connector code: {}", config.code);
-    connector_worker::spawn_connector_worker(connector_channel, tunnel_channels, config).await?;
+    utils::init_udpflow();
+    if let Some(size) = config.buffer_size {
+        utils::buffer_pool::set_buffer_size(size);
+    }
+    if let Some(port) = config.metrics_port {
+        metrics::spawn_metrics_server(port).await?;
+    }
+    if let Some(port) = config.punch_port {
+        punch::spawn_rendezvous_server(port).await?;
+    }
+
+    connector_worker::spawn_connector_worker(tunnel_channels, config).await?;
 
     tokio::signal::ctrl_c().await?;
     Ok(())
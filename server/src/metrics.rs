@@ -0,0 +1,196 @@
+use color_eyre::Result;
+use lazy_static::lazy_static;
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use utils::bandwidth::Bucket;
+
+lazy_static! {
+    /// Bandwidth buckets in use, dropped ones are pruned on next scrape or
+    /// registration
+    static ref BUCKETS: Mutex<Vec<Weak<Bucket>>> = Mutex::new(Vec::new());
+}
+
+/// Buckets are registered only when metrics are served
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Why a connection was closed without (or stopped) being forwarded
+#[derive(Clone, Copy)]
+pub enum Reason {
+    HandshakeTimeout,
+    AuthFailure,
+    TunnelTimeout,
+    IdleTimeout,
+}
+
+const REASONS: [Reason; 4] = [
+    Reason::HandshakeTimeout,
+    Reason::AuthFailure,
+    Reason::TunnelTimeout,
+    Reason::IdleTimeout,
+];
+
+/// Closed connections counted by `Reason`, counted even while metrics aren't
+/// served (it's only an increment)
+static CLOSED: [AtomicU64; REASONS.len()] = [const { AtomicU64::new(0) }; REASONS.len()];
+
+impl Reason {
+    fn label(self) -> &'static str {
+        match self {
+            Reason::HandshakeTimeout => "handshake_timeout",
+            Reason::AuthFailure => "auth_failure",
+            Reason::TunnelTimeout => "tunnel_timeout",
+            Reason::IdleTimeout => "idle_timeout",
+        }
+    }
+}
+
+pub fn record(reason: Reason) {
+    CLOSED[reason as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// `CopyOptions::on_idle` of forwarded connections
+pub fn record_idle() {
+    record(Reason::IdleTimeout);
+}
+
+/// Creates bandwidth bucket (if `rate` is set) and registers it for metrics,
+/// `labels` are prometheus labels identifying it
+pub fn bucket(labels: String, rate: Option<u64>) -> Option<Arc<Bucket>> {
+    let bucket = Bucket::new(labels, rate?);
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Some(bucket);
+    }
+
+    if let Ok(mut buckets) = BUCKETS.lock() {
+        buckets.retain(|b| b.strong_count() > 0);
+        buckets.push(Arc::downgrade(&bucket));
+    }
+
+    Some(bucket)
+}
+
+pub async fn spawn_metrics_server(port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    println!("Metrics available on port {}", port);
+    ENABLED.store(true, Ordering::Relaxed);
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Metrics listener error: {:?}", e);
+                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            tokio::spawn(async move {
+                // only GET /metrics is served, request itself doesn't matter
+                let mut buf = [0; 1024];
+                let _ = socket.read(&mut buf).await?;
+
+                let body = render();
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                socket.write_all(head.as_bytes()).await?;
+                socket.write_all(body.as_bytes()).await?;
+                socket.shutdown().await?;
+
+                Ok::<(), color_eyre::Report>(())
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn render() -> String {
+    let buckets: Vec<Arc<Bucket>> = match BUCKETS.lock() {
+        Ok(mut buckets) => {
+            buckets.retain(|b| b.strong_count() > 0);
+            buckets.iter().filter_map(|b| b.upgrade()).collect()
+        }
+        Err(_) => Vec::new(),
+    };
+
+    let mut out = String::new();
+    let _ = writeln!(out, "# TYPE lf_bandwidth_limit_bytes gauge");
+    for b in buckets.iter() {
+        let _ = writeln!(out, "lf_bandwidth_limit_bytes{{{}}} {}", b.name, b.rate());
+    }
+
+    let _ = writeln!(out, "# TYPE lf_bandwidth_bytes_total counter");
+    for b in buckets.iter() {
+        let _ = writeln!(out, "lf_bandwidth_bytes_total{{{}}} {}", b.name, b.bytes());
+    }
+
+    let _ = writeln!(out, "# TYPE lf_bandwidth_throttled_seconds_total counter");
+    for b in buckets.iter() {
+        let _ = writeln!(
+            out,
+            "lf_bandwidth_throttled_seconds_total{{{}}} {:.3}",
+            b.name,
+            b.throttled().as_secs_f64()
+        );
+    }
+
+    let _ = writeln!(out, "# TYPE lf_bandwidth_throttling gauge");
+    for b in buckets.iter() {
+        let _ = writeln!(
+            out,
+            "lf_bandwidth_throttling{{{}}} {}",
+            b.name,
+            b.is_throttling() as u8
+        );
+    }
+
+    let _ = writeln!(out, "# TYPE lf_closed_connections_total counter");
+    for reason in REASONS {
+        let _ = writeln!(
+            out,
+            "lf_closed_connections_total{{reason=\"{}\"}} {}",
+            reason.label(),
+            CLOSED[reason as usize].load(Ordering::Relaxed)
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn closed(reason: &str) -> u64 {
+        let prefix = format!("lf_closed_connections_total{{reason=\"{}\"}} ", reason);
+        let line = render()
+            .lines()
+            .find_map(|l| l.strip_prefix(&prefix).map(str::to_string));
+        line.unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn closed_connections_are_counted_by_reason() {
+        let before = closed("handshake_timeout");
+        let idle = closed("idle_timeout");
+
+        record(Reason::HandshakeTimeout);
+        record(Reason::HandshakeTimeout);
+        record_idle();
+
+        assert_eq!(closed("handshake_timeout"), before + 2);
+        assert_eq!(closed("idle_timeout"), idle + 1);
+    }
+}
@@ -0,0 +1,435 @@
+use crate::{
+    auth::{self, Permission},
+    forward,
+    structs::Config,
+};
+use color_eyre::Result;
+use lazy_static::lazy_static;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    sync::{mpsc, oneshot, Mutex, RwLock},
+};
+use utils::{
+    quic,
+    socks::{self, Address},
+    MultiStream,
+};
+
+/// Seconds both clients have to show their endpoints on the punch port
+const RENDEZVOUS_TIMEOUT: u64 = 5;
+
+/// Rendezvous requests waiting for slow provider, more of them are refused
+const LISTENER_QUEUE_SIZE: usize = 16;
+
+/// Punch datagram is rendezvous id and role of the sender
+const PUNCH_DATAGRAM_SIZE: usize = 9;
+const CONSUMER: usize = 0;
+const PROVIDER: usize = 1;
+
+/// Both sides of one rendezvous, each gets endpoint and certificate
+/// fingerprint of the other one
+#[derive(Default)]
+struct Rendezvous {
+    name: String,
+    endpoints: [Option<SocketAddr>; 2],
+    fingerprints: [String; 2],
+    peers: [Option<oneshot::Sender<(SocketAddr, String)>>; 2],
+}
+
+lazy_static! {
+    /// Providers of direct services waiting for rendezvous requests
+    static ref LISTENERS: Arc<RwLock<HashMap<String, mpsc::Sender<u64>>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+    static ref RENDEZVOUS: Arc<Mutex<HashMap<u64, Rendezvous>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+}
+
+/// Listens for punch datagrams, source address of each is the public UDP
+/// endpoint of its client as seen from outside of its NAT
+pub async fn spawn_rendezvous_server(port: u16) -> Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", port)).await?;
+    println!("Hole punching rendezvous on port {}", port);
+
+    tokio::spawn(async move {
+        let mut buf = [0; PUNCH_DATAGRAM_SIZE];
+        loop {
+            let (n, addr) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("Rendezvous socket error: {:?}", e);
+                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            if n != PUNCH_DATAGRAM_SIZE || buf[8] as usize > PROVIDER {
+                continue;
+            }
+
+            let id = u64::from_be_bytes(buf[..8].try_into().expect("SHOULDNT ERROR"));
+            observe(id, buf[8] as usize, addr).await;
+        }
+    });
+
+    Ok(())
+}
+
+async fn observe(id: u64, role: usize, addr: SocketAddr) {
+    let mut rendezvous = RENDEZVOUS.lock().await;
+    let entry = match rendezvous.get_mut(&id) {
+        Some(entry) => entry,
+        None => return,
+    };
+    entry.endpoints[role] = Some(addr);
+
+    if let [Some(consumer), Some(provider)] = entry.endpoints {
+        let entry = rendezvous.remove(&id).expect("SHOULDNT ERROR");
+        let [consumer_peer, provider_peer] = entry.peers;
+        let [consumer_fingerprint, provider_fingerprint] = entry.fingerprints;
+        if let Some(peer) = consumer_peer {
+            let _ = peer.send((provider, provider_fingerprint));
+        }
+        if let Some(peer) = provider_peer {
+            let _ = peer.send((consumer, consumer_fingerprint));
+        }
+    }
+}
+
+/// Keeps provider of a direct service registered while the connection lives
+/// and sends it ids of new rendezvous. Newer provider of the same service
+/// takes it over (like with ports).
+pub async fn listen(mut socket: MultiStream, config: &Config, addr: SocketAddr) -> Result<()> {
+    let token = utils::read_hostname(&mut socket).await?;
+    let name = utils::read_hostname(&mut socket).await?.to_lowercase();
+
+    if config.punch_port.is_none() {
+        return forward::reply(socket, socks::COMMAND_NOT_SUPPORTED).await;
+    }
+    if !auth::is_permitted(config, &token, &name, Permission::Expose) {
+        eprintln!("{} isn't allowed to expose service {}", addr, name);
+        return forward::reply(socket, socks::NOT_ALLOWED).await;
+    }
+
+    let (sender, mut receiver) = mpsc::channel(LISTENER_QUEUE_SIZE);
+    LISTENERS.write().await.insert(name.clone(), sender.clone());
+    socket.write_u8(socks::SUCCEEDED).await?;
+    socket.flush().await?;
+    println!("Direct paths enabled for service {}", name);
+
+    let mut closed = [0; 1];
+    let res = loop {
+        tokio::select! {
+            id = receiver.recv() => {
+                let id = match id {
+                    Some(id) => id,
+                    None => break Ok(()),
+                };
+
+                if let Err(e) = socket.write_u64(id).await {
+                    break Err(e.into());
+                }
+                if let Err(e) = socket.flush().await {
+                    break Err(e.into());
+                }
+            }
+            res = socket.read(&mut closed) => match res {
+                Ok(0) => break Ok(()),
+                Ok(_) => {}
+                Err(e) => break Err(e.into()),
+            }
+        }
+    };
+
+    let mut listeners = LISTENERS.write().await;
+    if listeners
+        .get(&name)
+        .is_some_and(|s| s.same_channel(&sender))
+    {
+        listeners.remove(&name);
+    }
+    res
+}
+
+/// Consumer starts rendezvous with id 0 and gets new one, provider joins it
+/// with the id it got from `listen`. Both then send punch datagrams with the
+/// id and get endpoint of the other side when the server saw both, together
+/// with fingerprint of the certificate it will present on the direct path.
+pub async fn connect(mut socket: MultiStream, config: &Config, addr: SocketAddr) -> Result<()> {
+    let token = utils::read_hostname(&mut socket).await?;
+    let name = utils::read_hostname(&mut socket).await?.to_lowercase();
+    let id = socket.read_u64().await?;
+    let fingerprint = utils::read_hostname(&mut socket).await?;
+
+    if config.punch_port.is_none() {
+        return forward::reply(socket, socks::COMMAND_NOT_SUPPORTED).await;
+    }
+    let fingerprint = match quic::parse_fingerprint(&fingerprint) {
+        Some(fingerprint) => fingerprint,
+        None => return forward::reply(socket, socks::GENERAL_FAILURE).await,
+    };
+
+    let (role, permission) = match id {
+        0 => (CONSUMER, Permission::Consume),
+        _ => (PROVIDER, Permission::Expose),
+    };
+    if !auth::is_permitted(config, &token, &name, permission) {
+        eprintln!("{} isn't allowed to punch service {}", addr, name);
+        return forward::reply(socket, socks::NOT_ALLOWED).await;
+    }
+
+    let (peer, peer_receiver) = oneshot::channel();
+    let id = match role {
+        CONSUMER => match start(&name, peer, fingerprint).await {
+            Some(id) => id,
+            None => return forward::reply(socket, socks::HOST_UNREACHABLE).await,
+        },
+        _ => {
+            if !join(id, &name, peer, fingerprint).await {
+                return forward::reply(socket, socks::HOST_UNREACHABLE).await;
+            }
+            id
+        }
+    };
+
+    let reply = async {
+        socket.write_u8(socks::SUCCEEDED).await?;
+        socket.write_u64(id).await?;
+        socket.flush().await
+    };
+    if let Err(e) = reply.await {
+        RENDEZVOUS.lock().await.remove(&id);
+        return Err(e.into());
+    }
+
+    // client sends nothing more, anything read means it gave up
+    let timeout = Duration::from_secs(RENDEZVOUS_TIMEOUT);
+    let mut closed = [0; 1];
+    let (peer, peer_fingerprint) = tokio::select! {
+        peer = tokio::time::timeout(timeout, peer_receiver) => match peer {
+            Ok(Ok(peer)) => peer,
+            _ => {
+                RENDEZVOUS.lock().await.remove(&id);
+                eprintln!("Rendezvous for service {} timed out", name);
+                return Ok(());
+            }
+        },
+        _ = socket.read(&mut closed) => {
+            RENDEZVOUS.lock().await.remove(&id);
+            eprintln!("Rendezvous for service {} abandoned", name);
+            return Ok(());
+        }
+    };
+
+    let mut bytes = vec![];
+    Address::Ip(peer).encode(&mut bytes)?;
+    utils::write_hostname(&mut bytes, &peer_fingerprint).await?;
+    socket.write_all(&bytes).await?;
+    socket.flush().await?;
+    Ok(())
+}
+
+/// Creates rendezvous and asks provider of the service to join it
+async fn start(
+    name: &str,
+    peer: oneshot::Sender<(SocketAddr, String)>,
+    fingerprint: String,
+) -> Option<u64> {
+    let listener = LISTENERS.read().await.get(name).cloned()?;
+
+    let id = loop {
+        let id = rand::random::<u64>();
+        if id != 0 {
+            break id;
+        }
+    };
+    let mut entry = Rendezvous {
+        name: name.to_string(),
+        ..Default::default()
+    };
+    entry.peers[CONSUMER] = Some(peer);
+    entry.fingerprints[CONSUMER] = fingerprint;
+    RENDEZVOUS.lock().await.insert(id, entry);
+
+    if listener.try_send(id).is_err() {
+        RENDEZVOUS.lock().await.remove(&id);
+        return None;
+    }
+    Some(id)
+}
+
+async fn join(
+    id: u64,
+    name: &str,
+    peer: oneshot::Sender<(SocketAddr, String)>,
+    fingerprint: String,
+) -> bool {
+    let mut rendezvous = RENDEZVOUS.lock().await;
+    match rendezvous.get_mut(&id) {
+        Some(entry) if entry.name == name && entry.peers[PROVIDER].is_none() => {
+            entry.peers[PROVIDER] = Some(peer);
+            entry.fingerprints[PROVIDER] = fingerprint;
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::quic::Identity;
+
+    /// Sends punch datagrams from `socket` until the server answers its side
+    async fn punch(
+        socket: &UdpSocket,
+        server: SocketAddr,
+        id: u64,
+        role: usize,
+        peer: oneshot::Receiver<(SocketAddr, String)>,
+    ) -> (SocketAddr, String) {
+        let mut datagram = id.to_be_bytes().to_vec();
+        datagram.push(role as u8);
+        tokio::pin!(peer);
+
+        loop {
+            socket.send_to(&datagram, server).await.unwrap();
+            tokio::select! {
+                peer = &mut peer => return peer.unwrap(),
+                _ = tokio::time::sleep(tokio::time::Duration::from_millis(100)) => {}
+            }
+        }
+    }
+
+    /// Consumer and provider sockets after rendezvous on loopback, each with
+    /// endpoint and fingerprint of the other side it got from the server
+    async fn rendezvous(
+        name: &str,
+        consumer: &Identity,
+        provider: &Identity,
+    ) -> [(UdpSocket, SocketAddr, String); 2] {
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        spawn_rendezvous_server(port).await.unwrap();
+        let server: SocketAddr = ([127, 0, 0, 1], port).into();
+
+        let (listener, mut ids) = mpsc::channel(LISTENER_QUEUE_SIZE);
+        LISTENERS.write().await.insert(name.to_string(), listener);
+
+        let (consumer_peer, consumer_receiver) = oneshot::channel();
+        let id = start(name, consumer_peer, consumer.fingerprint())
+            .await
+            .unwrap();
+        assert_eq!(ids.recv().await, Some(id));
+
+        let (provider_peer, provider_receiver) = oneshot::channel();
+        assert!(!join(id, "other", oneshot::channel().0, String::new()).await);
+        assert!(join(id, name, provider_peer, provider.fingerprint()).await);
+
+        let consumer_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let provider_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (consumer_seen, provider_seen) = tokio::join!(
+            punch(&consumer_socket, server, id, CONSUMER, consumer_receiver),
+            punch(&provider_socket, server, id, PROVIDER, provider_receiver),
+        );
+        LISTENERS.write().await.remove(name);
+
+        [
+            (consumer_socket, consumer_seen.0, consumer_seen.1),
+            (provider_socket, provider_seen.0, provider_seen.1),
+        ]
+    }
+
+    #[tokio::test]
+    async fn peers_connect_with_exchanged_fingerprints() {
+        let (consumer, provider) = (Identity::generate().unwrap(), Identity::generate().unwrap());
+        let [consumer_side, provider_side] = rendezvous("exchanged", &consumer, &provider).await;
+        let (consumer_socket, provider_addr, provider_fingerprint) = consumer_side;
+        let (provider_socket, consumer_addr, consumer_fingerprint) = provider_side;
+
+        assert_eq!(provider_addr, provider_socket.local_addr().unwrap());
+        assert_eq!(consumer_addr, consumer_socket.local_addr().unwrap());
+        assert_eq!(provider_fingerprint, provider.fingerprint());
+        assert_eq!(consumer_fingerprint, consumer.fingerprint());
+
+        let accepting = quic::peer_endpoint(
+            provider_socket.into_std().unwrap(),
+            &provider,
+            Some(&consumer_fingerprint),
+        )
+        .unwrap();
+        let connecting =
+            quic::peer_endpoint(consumer_socket.into_std().unwrap(), &consumer, None).unwrap();
+
+        let server = tokio::spawn(async move {
+            let peer = quic::QuicPeer::new(accepting.accept().await.unwrap().await.unwrap());
+            let mut stream = peer.accept_bi().await.unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.flush().await.unwrap();
+            stream.read_u8().await.ok();
+        });
+
+        let peer = quic::connect_peer(&connecting, provider_addr, &consumer, &provider_fingerprint)
+            .await
+            .unwrap();
+        let mut stream = peer.open_bi().await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        drop(stream);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn peers_reject_other_certificates() {
+        let (consumer, provider) = (Identity::generate().unwrap(), Identity::generate().unwrap());
+        let stranger = Identity::generate().unwrap();
+        let [(consumer_socket, provider_addr, _), (provider_socket, _, consumer_fingerprint)] =
+            rendezvous("rejected", &consumer, &provider).await;
+
+        let accepting = quic::peer_endpoint(
+            provider_socket.into_std().unwrap(),
+            &provider,
+            Some(&consumer_fingerprint),
+        )
+        .unwrap();
+
+        // consumer pins certificate the provider doesn't have
+        let connecting =
+            quic::peer_endpoint(consumer_socket.into_std().unwrap(), &consumer, None).unwrap();
+        let res = quic::connect_peer(
+            &connecting,
+            provider_addr,
+            &consumer,
+            &stranger.fingerprint(),
+        )
+        .await;
+        assert!(res.is_err());
+        assert!(accepting.accept().await.unwrap().await.is_err());
+
+        // provider doesn't accept certificate it didn't get from the rendezvous
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let connecting = quic::peer_endpoint(socket.into_std().unwrap(), &stranger, None).unwrap();
+        let client = tokio::spawn(async move {
+            quic::connect_peer(
+                &connecting,
+                provider_addr,
+                &stranger,
+                &provider.fingerprint(),
+            )
+            .await
+            .map(|_| ())
+        });
+        assert!(accepting.accept().await.unwrap().await.is_err());
+        client.abort();
+    }
+}
@@ -0,0 +1,106 @@
+use crate::{
+    auth::{self, Permission},
+    forward, metrics,
+    structs::{Config, TunnelKey},
+    tunnel, TunnelChannels,
+};
+use color_eyre::Result;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use utils::{compression::Compression, socks, CopyOptions, MultiStream};
+
+/// Connects consumer of a service with new tunnel of the client exposing it,
+/// nothing listens on the server for services
+pub async fn connect(
+    mut socket: MultiStream,
+    config: &Config,
+    tunnel_channels: &TunnelChannels,
+    addr: SocketAddr,
+) -> Result<()> {
+    let (compression, token, key) = read_request(&mut socket).await?;
+    let name = key.hostname.clone().unwrap_or_default();
+
+    if !auth::is_permitted(config, &token, &name, Permission::Consume) {
+        eprintln!("{} isn't allowed to consume service {}", addr, name);
+        return forward::reply(socket, socks::NOT_ALLOWED).await;
+    }
+
+    let route = match tunnel::pick_route(&key).await {
+        Some(route) => route,
+        None => {
+            eprintln!("Service {} isn't exposed", name);
+            return forward::reply(socket, socks::HOST_UNREACHABLE).await;
+        }
+    };
+    let _guard = match route.limiter.acquire(addr.ip()) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("Rejected {} on service {}: {}", addr, name, e);
+            return forward::reply(socket, socks::GENERAL_FAILURE).await;
+        }
+    };
+
+    let (tunnel, route) = match tunnel::request_tunnel(tunnel_channels, &key, route).await {
+        Some(requested) => requested,
+        None => {
+            eprintln!(
+                "Tunnel timed out (service {}), client didn't dial back",
+                name
+            );
+            return forward::reply(socket, socks::HOST_UNREACHABLE).await;
+        }
+    };
+
+    socket.write_u8(socks::SUCCEEDED).await?;
+    socket.flush().await?;
+
+    socket
+        .compressed(compression)
+        .copy_bidirectional(
+            tunnel,
+            &CopyOptions {
+                idle_timeout: route.port.idle_timeout(),
+                limits: route.limits.clone(),
+                on_idle: Some(metrics::record_idle),
+            },
+        )
+        .await
+}
+
+/// Reads tunnel dialed back by the client exposing a service, it's returned
+/// with its key and request id only if the token may expose the service
+pub async fn read_tunnel(
+    mut socket: MultiStream,
+    config: &Config,
+    addr: SocketAddr,
+) -> Result<Option<((TunnelKey, u64), MultiStream)>> {
+    let (compression, token, key) = read_request(&mut socket).await?;
+    let request_id = socket.read_u64().await?;
+    let name = key.hostname.clone().unwrap_or_default();
+
+    if !auth::is_permitted(config, &token, &name, Permission::Expose) {
+        eprintln!("{} isn't allowed to expose service {}", addr, name);
+        forward::reply(socket, socks::NOT_ALLOWED).await?;
+        return Ok(None);
+    }
+
+    socket.write_u8(socks::SUCCEEDED).await?;
+    socket.flush().await?;
+
+    Ok(Some(((key, request_id), socket.compressed(compression))))
+}
+
+/// Services are keyed by their name, they have no port
+async fn read_request(
+    socket: &mut MultiStream,
+) -> Result<(Option<Compression>, String, TunnelKey)> {
+    let compression = forward::read_compression(socket).await?;
+    let token = utils::read_hostname(socket).await?;
+    let name = utils::read_hostname(socket).await?.to_lowercase();
+
+    let key = TunnelKey {
+        port: 0,
+        hostname: Some(name),
+    };
+    Ok((compression, token, key))
+}
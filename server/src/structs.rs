@@ -1,4 +1,6 @@
-use std::{fs::Permissions, os::unix::prelude::PermissionsExt, path::PathBuf};
+use std::{
+    collections::HashMap, fs::Permissions, os::unix::prelude::PermissionsExt, path::PathBuf,
+};
 
 use color_eyre::Result;
 use serde::{Deserialize, Serialize};
@@ -7,10 +9,49 @@ use serde::{Deserialize, Serialize};
 pub struct Config {
     pub code: u64,
     pub port: u16,
+    pub quic_port: Option<u16>,
+    pub tunnel_timeout: Option<u64>,
+    pub buffer_size: Option<usize>,
+    pub bandwidth_limit: Option<u64>,
+    pub session_bandwidth_limit: Option<u64>,
+    pub metrics_port: Option<u16>,
+    pub handshake_timeout: Option<u64>,
+    pub max_auth_failures: Option<u32>,
+    pub ban_duration: Option<u64>,
+    pub noise: Option<bool>,
+
+    /// `host:port` targets local and dynamic forwards of clients can reach,
+    /// denied ones win
+    pub allowed_targets: Option<Vec<String>>,
+    pub denied_targets: Option<Vec<String>>,
+
+    /// Client tokens with services they can expose and consume
+    pub tokens: Option<HashMap<String, TokenPermissions>>,
+
+    /// UDP port where clients of direct services show their public endpoints
+    pub punch_port: Option<u16>,
+}
+
+/// Services a token grants access to, `*` matches every service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenPermissions {
+    #[serde(default)]
+    pub expose: Vec<String>,
+    #[serde(default)]
+    pub consume: Vec<String>,
+}
+
+/// Identifies a forwarded port on the server. Http ports share their `port`
+/// between many hostnames, so the hostname is part of the key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TunnelKey {
+    pub port: u16,
+    pub hostname: Option<String>,
 }
 
-const CONFIG_DIR: &str = "/etc/local-forwarder";
+pub const CONFIG_DIR: &str = "/etc/local-forwarder";
 const CONFIG_FILE: &str = "config.json";
+pub const CERTS_DIR: &str = "/etc/local-forwarder/certs";
 
 impl Config {
     pub async fn load() -> Result<Self> {
@@ -30,6 +71,20 @@ impl Config {
             let config = Config {
                 code: rand::random::<u64>(),
                 port: 1337,
+                quic_port: None,
+                tunnel_timeout: None,
+                buffer_size: None,
+                bandwidth_limit: None,
+                session_bandwidth_limit: None,
+                metrics_port: None,
+                handshake_timeout: None,
+                max_auth_failures: None,
+                ban_duration: None,
+                noise: None,
+                allowed_targets: None,
+                denied_targets: None,
+                tokens: None,
+                punch_port: None,
             };
 
             let config_str = serde_json::to_string_pretty(&config)?;
@@ -44,6 +99,62 @@ impl Config {
         Ok(Self {
             code: std::env::var("LF_CODE")?.parse()?,
             port: std::env::var("LF_PORT")?.parse()?,
+            quic_port: match std::env::var("LF_QUIC_PORT") {
+                Ok(port) => Some(port.parse()?),
+                Err(_) => None,
+            },
+            tunnel_timeout: match std::env::var("LF_TUNNEL_TIMEOUT") {
+                Ok(timeout) => Some(timeout.parse()?),
+                Err(_) => None,
+            },
+            buffer_size: match std::env::var("LF_BUFFER_SIZE") {
+                Ok(size) => Some(size.parse()?),
+                Err(_) => None,
+            },
+            bandwidth_limit: match std::env::var("LF_BANDWIDTH_LIMIT") {
+                Ok(limit) => Some(limit.parse()?),
+                Err(_) => None,
+            },
+            session_bandwidth_limit: match std::env::var("LF_SESSION_BANDWIDTH_LIMIT") {
+                Ok(limit) => Some(limit.parse()?),
+                Err(_) => None,
+            },
+            metrics_port: match std::env::var("LF_METRICS_PORT") {
+                Ok(port) => Some(port.parse()?),
+                Err(_) => None,
+            },
+            handshake_timeout: match std::env::var("LF_HANDSHAKE_TIMEOUT") {
+                Ok(timeout) => Some(timeout.parse()?),
+                Err(_) => None,
+            },
+            max_auth_failures: match std::env::var("LF_MAX_AUTH_FAILURES") {
+                Ok(max) => Some(max.parse()?),
+                Err(_) => None,
+            },
+            ban_duration: match std::env::var("LF_BAN_DURATION") {
+                Ok(duration) => Some(duration.parse()?),
+                Err(_) => None,
+            },
+            noise: match std::env::var("LF_NOISE") {
+                Ok(noise) => Some(noise.parse()?),
+                Err(_) => None,
+            },
+            allowed_targets: match std::env::var("LF_ALLOWED_TARGETS") {
+                Ok(targets) => Some(targets.split(',').map(|t| t.trim().to_string()).collect()),
+                Err(_) => None,
+            },
+            denied_targets: match std::env::var("LF_DENIED_TARGETS") {
+                Ok(targets) => Some(targets.split(',').map(|t| t.trim().to_string()).collect()),
+                Err(_) => None,
+            },
+            tokens: match std::env::var("LF_TOKENS") {
+                Ok(tokens) => Some(serde_json::from_str(&tokens)?),
+                Err(_) => None,
+            },
+            punch_port: match std::env::var("LF_PUNCH_PORT") {
+                Ok(port) => Some(port.parse()?),
+                Err(_) => None,
+            },
         })
     }
 
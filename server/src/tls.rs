@@ -0,0 +1,175 @@
+use crate::structs::{CERTS_DIR, CONFIG_DIR};
+use color_eyre::Result;
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    fs::Permissions,
+    io::BufReader,
+    os::unix::prelude::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+use tokio::sync::OnceCell;
+use tokio_rustls::{
+    rustls::{
+        server::{ClientHello, ResolvesServerCert},
+        sign::{self, CertifiedKey},
+        Certificate, PrivateKey, ServerConfig,
+    },
+    TlsAcceptor,
+};
+use utils::quic::Identity;
+
+const RELOAD_INTERVAL: u64 = 10;
+
+/// QUIC certificate and key (DER) in `CONFIG_DIR`
+const QUIC_CERT_FILE: &str = "quic-cert.der";
+const QUIC_KEY_FILE: &str = "quic-key.der";
+
+/// Certificates loaded from `CERTS_DIR`, stored as `<hostname>.crt` and
+/// `<hostname>.key` (PEM) and picked by SNI
+#[derive(Default)]
+pub struct CertStore {
+    certs: RwLock<HashMap<String, (SystemTime, Arc<CertifiedKey>)>>,
+}
+
+lazy_static! {
+    static ref CERT_STORE: Arc<CertStore> = Arc::new(CertStore::default());
+    static ref ACCEPTOR: TlsAcceptor = {
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(CERT_STORE.clone());
+
+        TlsAcceptor::from(Arc::new(config))
+    };
+}
+
+static RELOADER: OnceCell<()> = OnceCell::const_new();
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let hostname = client_hello.server_name()?.to_lowercase();
+        let certs = self.certs.read().ok()?;
+
+        certs.get(&hostname).map(|(_, key)| key.clone())
+    }
+}
+
+impl CertStore {
+    /// Loads new and changed certificates, forgets removed ones
+    fn reload(&self) -> Result<()> {
+        let mut found: HashMap<String, SystemTime> = HashMap::new();
+        for entry in std::fs::read_dir(CERTS_DIR)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("crt") {
+                continue;
+            }
+
+            let key_path = path.with_extension("key");
+            let hostname = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(hostname) if key_path.exists() => hostname.to_lowercase(),
+                _ => continue,
+            };
+
+            let modified = std::fs::metadata(&path)?
+                .modified()?
+                .max(std::fs::metadata(&key_path)?.modified()?);
+            found.insert(hostname, modified);
+        }
+
+        let mut certs = self
+            .certs
+            .write()
+            .map_err(|_| color_eyre::eyre::eyre!("Certificate store poisoned"))?;
+        certs.retain(|hostname, _| found.contains_key(hostname));
+
+        for (hostname, modified) in found {
+            if certs.get(&hostname).map(|(m, _)| *m) == Some(modified) {
+                continue;
+            }
+
+            let path = PathBuf::from(CERTS_DIR).join(&hostname);
+            match load_certified_key(&path.with_extension("crt"), &path.with_extension("key")) {
+                Ok(key) => {
+                    println!("Loaded certificate for {}", hostname);
+                    certs.insert(hostname, (modified, Arc::new(key)));
+                }
+                Err(e) => eprintln!("Failed to load certificate for {}: {:?}", hostname, e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let mut reader = BufReader::new(std::fs::File::open(cert_path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+
+    let mut reader = BufReader::new(std::fs::File::open(key_path)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => break PrivateKey(key),
+            Some(_) => continue,
+            None => color_eyre::eyre::bail!("No private key in {:?}", key_path),
+        }
+    };
+
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| color_eyre::eyre::eyre!("Unsupported private key in {:?}", key_path))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Returns acceptor terminating TLS with certificates from `CERTS_DIR`.
+/// First call loads them and spawns task reloading them when they change.
+pub async fn acceptor() -> Result<TlsAcceptor> {
+    RELOADER
+        .get_or_try_init(|| async {
+            tokio::fs::create_dir_all(CERTS_DIR).await?;
+            CERT_STORE.reload()?;
+
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(tokio::time::Duration::from_secs(RELOAD_INTERVAL)).await;
+                    if let Err(e) = CERT_STORE.reload() {
+                        eprintln!("Certificate reload error: {:?}", e);
+                    }
+                }
+            });
+
+            Ok::<(), color_eyre::Report>(())
+        })
+        .await?;
+
+    Ok(ACCEPTOR.clone())
+}
+
+/// Returns certificate of the QUIC listener, generated the first time and
+/// kept in `CONFIG_DIR` so clients can pin its fingerprint. If it can't be
+/// saved there, new one is generated on every start.
+pub fn quic_identity() -> Result<Identity> {
+    let dir = PathBuf::from(CONFIG_DIR);
+    let (cert_path, key_path) = (dir.join(QUIC_CERT_FILE), dir.join(QUIC_KEY_FILE));
+    if let (Ok(cert), Ok(key)) = (std::fs::read(&cert_path), std::fs::read(&key_path)) {
+        return Ok(Identity { cert, key });
+    }
+
+    let identity = Identity::generate()?;
+    let saved = std::fs::create_dir_all(&dir)
+        .and_then(|_| std::fs::write(&key_path, &identity.key))
+        .and_then(|_| std::fs::set_permissions(&key_path, Permissions::from_mode(0o600)))
+        .and_then(|_| std::fs::write(&cert_path, &identity.cert));
+    if let Err(e) = saved {
+        eprintln!("Could not save QUIC certificate to {:?}: {}", dir, e);
+    }
+
+    Ok(identity)
+}
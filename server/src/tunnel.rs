@@ -1,98 +1,460 @@
-use crate::{channeled_channel, ConnectorChannel};
+use crate::{
+    limiter::ConnectionLimiter, metrics, structs::TunnelKey, vhost, ConnectorChannel,
+    TunnelChannels,
+};
 use color_eyre::Result;
 use lazy_static::lazy_static;
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use tokio::{
     net::{TcpListener, UdpSocket},
     sync::RwLock,
     task::JoinHandle,
 };
 use udpflow::UdpListener;
-use utils::{ConnectorPort, MultiStream, PortType};
+use utils::{
+    bandwidth::{Bucket, Limits},
+    ConnectorPort, CopyOptions, Direction, MultiStream, PortType,
+};
 
 pub const BUFFER_SIZE: usize = 65536;
 
+/// Seconds session of a pool which didn't dial back is skipped by new
+/// connections
+const MEMBER_RETRY: u64 = 10;
+
+/// Registration of a forwarded port by client sessions, only pooled ports
+/// have more of them
+pub struct Route {
+    members: Vec<Member>,
+    next: AtomicUsize,
+    task: Option<JoinHandle<()>>,
+    /// Connection limits and port bandwidth are the route's, members of a
+    /// pool share them
+    limiter: Arc<ConnectionLimiter>,
+    bandwidth: Option<Arc<Bucket>>,
+}
+
+struct Member {
+    info: RouteInfo,
+    failed_at: Option<Instant>,
+}
+
+/// Everything connections of a route need, cheap to clone
+#[derive(Clone)]
+pub struct RouteInfo {
+    pub session: u64,
+    pub connector_sender: async_channel::Sender<(TunnelKey, u64)>,
+    pub port: ConnectorPort,
+    pub limits: Limits,
+    pub limiter: Arc<ConnectionLimiter>,
+}
+
 lazy_static! {
-    pub static ref TUNNEL_TASKS: Arc<RwLock<Vec<JoinHandle<()>>>> =
-        Arc::new(RwLock::new(Vec::new()));
+    pub static ref ROUTES: Arc<RwLock<HashMap<TunnelKey, Route>>> =
+        Arc::new(RwLock::new(HashMap::new()));
 }
 
 pub async fn spawn_multiple_tunnels(
-    tunnel_channels: channeled_channel::ChanneledChannel<MultiStream>,
+    tunnel_channels: TunnelChannels,
     connector_channel: ConnectorChannel,
+    session: u64,
+    limits: Limits,
     ports: Vec<ConnectorPort>,
 ) -> Result<()> {
-    tunnel_channels.remove_all_channels().await?;
-    for task in TUNNEL_TASKS.write().await.drain(..) {
-        task.abort();
-    }
+    // local forwards are dialed by their tunnels, there's nothing to listen on
+    for port in ports
+        .into_iter()
+        .filter(|p| matches!(p.direction, Direction::Remote | Direction::Service))
+    {
+        // one rejected port doesn't take the others down
+        let res = spawn_tunnel(
+            tunnel_channels.clone(),
+            connector_channel.clone(),
+            session,
+            limits.clone(),
+            port,
+        )
+        .await;
 
-    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-    for port in ports {
-        spawn_tunnel(tunnel_channels.clone(), connector_channel.clone(), port).await?;
+        if let Err(e) = res {
+            eprintln!("Port not registered: {}", e);
+        }
     }
 
     Ok(())
 }
 
 pub async fn spawn_tunnel(
-    tunnel_channels: channeled_channel::ChanneledChannel<MultiStream>,
+    tunnel_channels: TunnelChannels,
     connector_channel: ConnectorChannel,
+    session: u64,
+    limits: Limits,
     port: ConnectorPort,
 ) -> Result<()> {
-    println!(
-        "Spawning {:?} tunnel on port {}",
-        port.port_type, port.port_remote
-    );
-    tunnel_channels.create_channel(&port.port_remote).await?;
-
-    let task = tokio::spawn(async move {
-        loop {
-            let res = match port.port_type {
-                PortType::Tcp => {
-                    proxy_tunnel_tcp(&tunnel_channels, &connector_channel, &port.port_remote).await
-                }
-                PortType::Udp => {
-                    proxy_tunnel_udp(&tunnel_channels, &connector_channel, &port.port_remote).await
+    let mut key = TunnelKey {
+        port: port.port_remote,
+        hostname: None,
+    };
+
+    // services have no port on the server, just their name
+    if port.direction == Direction::Service {
+        key.port = 0;
+        key.hostname = port.service.as_ref().map(|s| s.to_lowercase());
+    } else if port.is_host_routed() {
+        let hostname = port.hostname.as_ref().ok_or_else(|| {
+            color_eyre::eyre::eyre!(
+                "Missing hostname for {:?} port {}",
+                port.port_type,
+                key.port
+            )
+        })?;
+
+        key.hostname = Some(hostname.to_lowercase());
+    }
+
+    // bucket of a pool isn't any session's
+    let owner = match port.pool {
+        true => String::from("pool"),
+        false => format!("{:016x}", session),
+    };
+    let labels = match &key.hostname {
+        Some(name) if key.port == 0 => format!(
+            "scope=\"service\",session=\"{}\",service=\"{}\"",
+            owner, name
+        ),
+        Some(hostname) => format!(
+            "scope=\"port\",session=\"{}\",port=\"{}\",hostname=\"{}\"",
+            owner, key.port, hostname
+        ),
+        None => format!("scope=\"port\",session=\"{}\",port=\"{}\"", owner, key.port),
+    };
+    let mut info = RouteInfo {
+        session,
+        connector_sender: connector_channel.0,
+        limits,
+        limiter: ConnectionLimiter::new(&port),
+        port,
+    };
+
+    let vhost_kind = match info.port.direction {
+        Direction::Remote if info.port.is_host_routed() => {
+            Some(vhost::ListenerKind::of(&info.port))
+        }
+        _ => None,
+    };
+    if let Some(kind) = vhost_kind {
+        vhost::check_kind(key.port, kind).await?;
+    }
+
+    // pooled sessions with the same settings share the port, otherwise last
+    // handshake wins and previous owner of this port (or hostname) is dropped
+    let old_route = {
+        let mut routes = ROUTES.write().await;
+        check_shared(&routes, &key)?;
+
+        match routes.get_mut(&key) {
+            Some(route) if route.can_join(&info.port) => {
+                println!("Session {:016x} joined pool of {}", session, describe(&key));
+                info.limiter = route.limiter.clone();
+                info.limits = info.limits.with(route.bandwidth.clone());
+                route.members.push(Member {
+                    info,
+                    failed_at: None,
+                });
+                return Ok(());
+            }
+            _ => routes.remove(&key),
+        }
+    };
+    if let Some(task) = old_route.and_then(|r| r.task) {
+        task.abort();
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
+
+    match &key.hostname {
+        Some(name) if key.port == 0 => println!("Exposing service {}", name),
+        Some(hostname) => println!(
+            "Spawning {:?} tunnel on port {} for {}",
+            info.port.port_type, key.port, hostname
+        ),
+        None => println!(
+            "Spawning {:?} tunnel on port {}",
+            info.port.port_type, key.port
+        ),
+    }
+    let bandwidth = metrics::bucket(labels, info.port.bandwidth_limit);
+    info.limits = info.limits.with(bandwidth.clone());
+
+    let task = if info.port.direction == Direction::Service || vhost_kind.is_some() {
+        None
+    } else {
+        let tunnel_channels = tunnel_channels.clone();
+        let key = key.clone();
+        let port_type = info.port.port_type.clone();
+
+        Some(tokio::spawn(async move {
+            loop {
+                let res = match port_type {
+                    PortType::Udp => proxy_tunnel_udp(&tunnel_channels, &key).await,
+                    _ => proxy_tunnel_tcp(&tunnel_channels, &key).await,
+                };
+
+                if let Err(e) = res {
+                    eprintln!("Tunnel error: {:?}", e);
+                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
                 }
-            };
+            }
+        }))
+    };
+
+    let limiter = info.limiter.clone();
+    let route = Route {
+        members: vec![Member {
+            info,
+            failed_at: None,
+        }],
+        next: AtomicUsize::new(0),
+        task,
+        limiter,
+        bandwidth,
+    };
 
-            if let Err(e) = res {
-                eprintln!("Tunnel error: {:?}", e);
-                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    // lock was released while the old owner stopped, so another session may
+    // have registered meanwhile, check again and let this handshake win
+    let replaced = {
+        let mut routes = ROUTES.write().await;
+        if let Err(e) = check_shared(&routes, &key) {
+            if let Some(task) = route.task {
+                task.abort();
             }
+            return Err(e);
         }
-    });
+        routes.insert(key.clone(), route)
+    };
+    if let Some(task) = replaced.and_then(|r| r.task) {
+        task.abort();
+    }
+
+    // listener is started only after the route exists, so it isn't stopped
+    // in between by the last other hostname leaving
+    if let Some(kind) = vhost_kind {
+        vhost::ensure_listener(tunnel_channels, key.port, kind).await;
+    }
 
-    TUNNEL_TASKS.write().await.push(task);
     Ok(())
 }
 
-async fn proxy_tunnel_tcp(
-    tunnel_channels: &channeled_channel::ChanneledChannel<MultiStream>,
-    connector_channel: &ConnectorChannel,
-    port: &u16,
-) -> Result<()> {
-    let listener = TcpListener::bind(("0.0.0.0", port.to_owned())).await?;
-    let channel = tunnel_channels
-        .get_receiver(&port)
-        .await
-        .ok_or_else(|| color_eyre::eyre::eyre!("Could not get receiver for port {}", port))?;
+impl Route {
+    /// Pool can be joined only by sessions which also want it and handle
+    /// tunnels the same way (listener, TLS termination, framing and
+    /// compression all follow from these)
+    fn can_join(&self, port: &ConnectorPort) -> bool {
+        port.pool
+            && self.members.iter().all(|member| {
+                let other = &member.info.port;
+                other.pool
+                    && other.direction == port.direction
+                    && other.port_type == port.port_type
+                    && other.tunnel_type == port.tunnel_type
+                    && other.terminate_tls == port.terminate_tls
+                    && other.compression() == port.compression()
+            })
+    }
+}
+
+impl Member {
+    fn is_healthy(&self) -> bool {
+        match self.failed_at {
+            Some(at) => at.elapsed() >= Duration::from_secs(MEMBER_RETRY),
+            None => true,
+        }
+    }
+}
+
+/// Port is either listened on by one plain tunnel or shared by hostnames,
+/// the other kind of registration is rejected until the port is free
+fn check_shared(routes: &HashMap<TunnelKey, Route>, key: &TunnelKey) -> Result<()> {
+    let host_routed = key.hostname.is_some();
+    let mixed = routes
+        .keys()
+        .any(|k| k.port == key.port && k.hostname.is_some() != host_routed);
+
+    match key.port != 0 && mixed {
+        true if host_routed => color_eyre::eyre::bail!(
+            "Port {} is already used by plain tunnel, rejecting {}",
+            key.port,
+            describe(key)
+        ),
+        true => color_eyre::eyre::bail!(
+            "Port {} is already shared by hostnames, rejecting plain tunnel",
+            key.port
+        ),
+        false => Ok(()),
+    }
+}
+
+pub fn describe(key: &TunnelKey) -> String {
+    match &key.hostname {
+        Some(name) if key.port == 0 => format!("service {}", name),
+        Some(hostname) => format!("port {} ({})", key.port, hostname),
+        None => format!("port {}", key.port),
+    }
+}
+
+/// Removes the session from all its routes, routes are removed with their
+/// last session (routes taken over by newer sessions are left alone)
+pub async fn remove_session(session: u64) -> Result<()> {
+    let mut routes = ROUTES.write().await;
+    let mut keys: Vec<TunnelKey> = vec![];
+    for (key, route) in routes.iter_mut() {
+        let members = route.members.len();
+        route
+            .members
+            .retain(|member| member.info.session != session);
+
+        if route.members.is_empty() {
+            keys.push(key.clone());
+        } else if route.members.len() != members {
+            println!("Session {:016x} left pool of {}", session, describe(key));
+        }
+    }
+
+    for key in keys {
+        if let Some(task) = routes.remove(&key).and_then(|r| r.task) {
+            task.abort();
+        }
+
+        let host_routed = |k: &TunnelKey| k.port == key.port && k.hostname.is_some();
+        if key.port != 0 && host_routed(&key) && !routes.keys().any(host_routed) {
+            vhost::remove_listener(key.port).await;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns connector sender, port settings and limits of the first session
+/// serving `key`
+pub async fn get_route(key: &TunnelKey) -> Option<RouteInfo> {
+    let routes = ROUTES.read().await;
+    let route = routes.get(key)?;
+    route.members.first().map(|member| member.info.clone())
+}
+
+/// Picks session for new connection, sessions of a pool take turns and the
+/// ones which recently didn't dial back are skipped while others are left
+pub async fn pick_route(key: &TunnelKey) -> Option<RouteInfo> {
+    let routes = ROUTES.read().await;
+    let route = routes.get(key)?;
+
+    let mut members: Vec<&Member> = route.members.iter().filter(|m| m.is_healthy()).collect();
+    if members.is_empty() {
+        members = route.members.iter().collect();
+    }
+    if members.is_empty() {
+        return None;
+    }
+
+    let next = route.next.fetch_add(1, Ordering::Relaxed);
+    Some(members[next % members.len()].info.clone())
+}
+
+/// Asks session for a tunnel, other sessions of a pool are asked in turn if
+/// it doesn't dial back in time. Returns the tunnel with the last asked
+/// session.
+///
+/// Every request has its own id the tunnel answers with, so late tunnel of
+/// a request which was already given up on finds no channel and is dropped
+/// instead of serving some other connection.
+pub async fn request_tunnel(
+    tunnel_channels: &TunnelChannels,
+    key: &TunnelKey,
+    mut route: RouteInfo,
+) -> Option<(MultiStream, RouteInfo)> {
+    let attempts = ROUTES.read().await.get(key).map_or(1, |r| r.members.len());
+    for attempt in 1..=attempts {
+        let request = (key.clone(), rand::random::<u64>());
+        tunnel_channels.create_channel(&request).await.ok()?;
+        let channel = tunnel_channels.get_receiver(&request).await?;
+
+        let mut tunnel = None;
+        if route.connector_sender.send(request.clone()).await.is_ok() {
+            let recv = tokio::time::timeout(route.port.tunnel_timeout(), channel.recv()).await;
+            tunnel = recv.ok().and_then(|t| t.ok());
+        }
+        tunnel_channels.remove_channel(&request).await.ok()?;
+
+        if let Some(tunnel) = tunnel {
+            return Some((tunnel, route));
+        }
+
+        metrics::record(metrics::Reason::TunnelTimeout);
+        mark_failed(key, route.session).await;
+        if attempt < attempts {
+            route = pick_route(key).await?;
+        }
+    }
+
+    None
+}
+
+async fn mark_failed(key: &TunnelKey, session: u64) {
+    let mut routes = ROUTES.write().await;
+    let members = routes.get_mut(key).map(|r| r.members.iter_mut());
+    for member in members.into_iter().flatten() {
+        if member.info.session == session {
+            member.failed_at = Some(Instant::now());
+        }
+    }
+}
+
+async fn proxy_tunnel_tcp(tunnel_channels: &TunnelChannels, key: &TunnelKey) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", key.port)).await?;
 
     loop {
-        let (remote, _) = listener.accept().await?;
+        let (remote, addr) = listener.accept().await?;
+        let route = match pick_route(key).await {
+            Some(route) => route,
+            None => continue,
+        };
+        let guard = match route.limiter.acquire(addr.ip()) {
+            Ok(guard) => guard,
+            Err(e) => {
+                eprintln!("Rejected {} on port {}: {}", addr, key.port, e);
+                continue;
+            }
+        };
         remote.set_nodelay(true)?;
 
-        connector_channel.0.send(*port).await?;
-        let channel = channel.clone();
+        let tunnel_channels = tunnel_channels.clone();
+        let key = key.clone();
 
         tokio::spawn(async move {
-            tokio::select! {
-                Ok(tunnel) = channel.recv() => {
-                    tunnel.copy_bidirectional(remote).await?;
-                },
-                _ = tokio::time::sleep(tokio::time::Duration::from_secs(1)) => {
-                    eprintln!("Tunnel timed out");
+            let _guard = guard;
+            match request_tunnel(&tunnel_channels, &key, route).await {
+                Some((tunnel, route)) => {
+                    let options = CopyOptions {
+                        idle_timeout: route.port.idle_timeout(),
+                        limits: route.limits.clone(),
+                        on_idle: Some(metrics::record_idle),
+                    };
+                    tunnel
+                        .framed(&PortType::Tcp)
+                        .copy_bidirectional_tcp(remote, &options)
+                        .await?;
+                }
+                None => {
+                    eprintln!(
+                        "Tunnel timed out (port {}), client didn't dial back",
+                        key.port
+                    );
                 }
             }
 
@@ -101,36 +463,117 @@ async fn proxy_tunnel_tcp(
     }
 }
 
-async fn proxy_tunnel_udp(
-    tunnel_channels: &channeled_channel::ChanneledChannel<MultiStream>,
-    connector_channel: &ConnectorChannel,
-    port: &u16,
-) -> Result<()> {
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", port)).await?;
+async fn proxy_tunnel_udp(tunnel_channels: &TunnelChannels, key: &TunnelKey) -> Result<()> {
+    let socket = UdpSocket::bind(format!("0.0.0.0:{}", key.port)).await?;
     let listener = UdpListener::new(socket);
 
-    let channel = tunnel_channels
-        .get_receiver(&port)
-        .await
-        .ok_or_else(|| color_eyre::eyre::eyre!("Could not get receiver for port {}", port))?;
-
-    let buffer = &mut [0u8; BUFFER_SIZE];
+    // one per listener, has to fit any datagram
+    let buffer = &mut vec![0u8; BUFFER_SIZE];
     loop {
-        let (remote, _) = listener.accept(&mut buffer[..]).await?;
+        let (remote, addr) = listener.accept(&mut buffer[..]).await?;
+        let route = match pick_route(key).await {
+            Some(route) => route,
+            None => continue,
+        };
+        let guard = match route.limiter.acquire(addr.ip()) {
+            Ok(guard) => guard,
+            Err(e) => {
+                eprintln!("Rejected UDP flow {} on port {}: {}", addr, key.port, e);
+                continue;
+            }
+        };
+
+        let tunnel_channels = tunnel_channels.clone();
+        let key = key.clone();
 
-        connector_channel.0.send(*port).await?;
-        let channel = channel.clone();
         tokio::spawn(async move {
-            tokio::select! {
-                Ok(tunnel) = channel.recv() => {
-                    tunnel.copy_bidirectional(remote).await?;
-                },
-                _ = tokio::time::sleep(tokio::time::Duration::from_secs(1)) => {
-                    eprintln!("Proxy worker timed out");
+            // flow is closed (dropped) at the end, guard makes room for new one
+            let _guard = guard;
+            match request_tunnel(&tunnel_channels, &key, route).await {
+                Some((tunnel, route)) => {
+                    let options = CopyOptions {
+                        idle_timeout: route.port.idle_timeout(),
+                        limits: route.limits.clone(),
+                        on_idle: Some(metrics::record_idle),
+                    };
+                    tunnel
+                        .framed(&PortType::Udp)
+                        .copy_bidirectional(remote, &options)
+                        .await
+                }
+                None => {
+                    eprintln!(
+                        "Tunnel timed out (port {}), client didn't dial back",
+                        key.port
+                    );
+                    Ok(())
                 }
             }
+        });
+    }
+}
 
-            Ok::<(), color_eyre::Report>(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn route(connector_sender: async_channel::Sender<(TunnelKey, u64)>) -> RouteInfo {
+        let port: ConnectorPort = serde_json::from_value(serde_json::json!({
+            "port_remote": 1,
+            "port_local": 1,
+            "local_ip": "127.0.0.1",
+            "port_type": "Tcp",
+            "tunnel_type": "Tcp",
+            "tunnel_timeout": 1,
+        }))
+        .unwrap();
+
+        RouteInfo {
+            session: 0,
+            connector_sender,
+            limiter: ConnectionLimiter::new(&port),
+            port,
+            limits: Limits::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn late_tunnel_is_not_given_to_next_request() {
+        let tunnel_channels = TunnelChannels::new();
+        let key = TunnelKey {
+            port: 1,
+            hostname: None,
+        };
+        let (sender, requests) = async_channel::unbounded::<(TunnelKey, u64)>();
+
+        // client answers the first request only after the second one came,
+        // both tunnels are sent in the order they were asked for
+        let channels = tunnel_channels.clone();
+        let client = tokio::spawn(async move {
+            let first = requests.recv().await.unwrap();
+            let second = requests.recv().await.unwrap();
+            assert_ne!(first.1, second.1);
+
+            let mut ends = vec![];
+            for (request, marker) in [(first, 1), (second, 2)] {
+                let (tunnel, mut end) = tokio::io::duplex(64);
+                end.write_u8(marker).await.unwrap();
+                if let Some(sender) = channels.get_sender(&request).await {
+                    sender.send(MultiStream::Reliable(tunnel)).await.unwrap();
+                }
+                ends.push(end);
+            }
+            ends
         });
+
+        let late = request_tunnel(&tunnel_channels, &key, route(sender.clone())).await;
+        assert!(late.is_none());
+
+        let (mut tunnel, _) = request_tunnel(&tunnel_channels, &key, route(sender))
+            .await
+            .unwrap();
+        assert_eq!(tunnel.read_u8().await.unwrap(), 2);
+        client.await.unwrap();
     }
 }
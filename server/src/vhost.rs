@@ -0,0 +1,418 @@
+use crate::{metrics, structs::TunnelKey, tls, tunnel, TunnelChannels};
+use color_eyre::Result;
+use lazy_static::lazy_static;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::RwLock,
+    task::JoinHandle,
+};
+use utils::{ConnectorPort, CopyOptions, PortType};
+
+const MAX_HEAD_SIZE: usize = 16384;
+/// Seconds incoming connection has to send its request head (or TLS
+/// ClientHello) in
+const HEAD_TIMEOUT: u64 = 10;
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_SNI_EXTENSION: u16 = 0x0000;
+const NOT_FOUND_RESPONSE: &[u8] =
+    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+const BAD_GATEWAY_RESPONSE: &[u8] =
+    b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+const UNAVAILABLE_RESPONSE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+/// How shared listener reads hostname of incoming connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListenerKind {
+    /// Host header of plain HTTP request
+    Http,
+    /// Host header of HTTP request after terminating TLS on the server
+    Https,
+    /// ClientHello SNI, stream itself stays encrypted
+    Tls,
+}
+
+impl ListenerKind {
+    pub fn of(port: &ConnectorPort) -> Self {
+        match port.port_type {
+            PortType::Tls => ListenerKind::Tls,
+            _ if port.terminate_tls => ListenerKind::Https,
+            _ => ListenerKind::Http,
+        }
+    }
+}
+
+type VhostListener = (ListenerKind, JoinHandle<()>);
+
+lazy_static! {
+    static ref LISTENERS: Arc<RwLock<HashMap<u16, VhostListener>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+/// Fails if the port is already shared by tunnels of other kind, one
+/// listener can't read hostnames both ways
+pub async fn check_kind(port: u16, kind: ListenerKind) -> Result<()> {
+    match LISTENERS.read().await.get(&port) {
+        Some((listener_kind, _)) if *listener_kind != kind => Err(color_eyre::eyre::eyre!(
+            "Port {} is already shared by {:?} tunnels, rejecting {:?}",
+            port,
+            listener_kind,
+            kind
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Spawns shared listener on the port (if it isn't running already)
+pub async fn ensure_listener(tunnel_channels: TunnelChannels, port: u16, kind: ListenerKind) {
+    let mut listeners = LISTENERS.write().await;
+    if listeners.contains_key(&port) {
+        return;
+    }
+
+    println!("Spawning {:?} listener on port {}", kind, port);
+    let task = tokio::spawn(async move {
+        loop {
+            if let Err(e) = vhost_listener(&tunnel_channels, port, kind).await {
+                eprintln!("{:?} listener error: {:?}", kind, e);
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+            }
+        }
+    });
+
+    listeners.insert(port, (kind, task));
+}
+
+/// Stops shared listener of the port, once its last hostname is gone the
+/// port can be registered as any other
+pub async fn remove_listener(port: u16) {
+    if let Some((kind, task)) = LISTENERS.write().await.remove(&port) {
+        println!("Stopping {:?} listener on port {}", kind, port);
+        task.abort();
+    }
+}
+
+async fn vhost_listener(
+    tunnel_channels: &TunnelChannels,
+    port: u16,
+    kind: ListenerKind,
+) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    let acceptor = match kind {
+        ListenerKind::Https => Some(tls::acceptor().await?),
+        _ => None,
+    };
+
+    loop {
+        let (mut remote, addr) = listener.accept().await?;
+        remote.set_nodelay(true)?;
+
+        let tunnel_channels = tunnel_channels.clone();
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            let timeout = tokio::time::Duration::from_secs(HEAD_TIMEOUT);
+            let timed_out = || color_eyre::eyre::eyre!("Request head timed out ({})", addr);
+
+            match (kind, acceptor) {
+                (ListenerKind::Https, Some(acceptor)) => {
+                    let head = async {
+                        let mut remote = acceptor.accept(remote).await?;
+                        let (hostname, head) = read_http_host(&mut remote).await?;
+                        Ok::<_, color_eyre::Report>((remote, hostname, head))
+                    };
+                    let (remote, hostname, head) = tokio::time::timeout(timeout, head)
+                        .await
+                        .map_err(|_| timed_out())??;
+                    forward(&tunnel_channels, port, addr, remote, hostname, head, true).await
+                }
+                (ListenerKind::Tls, _) => {
+                    let (hostname, head) = tokio::time::timeout(timeout, read_tls_sni(&mut remote))
+                        .await
+                        .map_err(|_| timed_out())??;
+                    forward(&tunnel_channels, port, addr, remote, hostname, head, false).await
+                }
+                _ => {
+                    let (hostname, head) =
+                        tokio::time::timeout(timeout, read_http_host(&mut remote))
+                            .await
+                            .map_err(|_| timed_out())??;
+                    forward(&tunnel_channels, port, addr, remote, hostname, head, true).await
+                }
+            }
+        });
+    }
+}
+
+/// Asks the client which registered `hostname` for a tunnel and forwards
+/// `remote` through it, starting with already read `head`
+async fn forward<T>(
+    tunnel_channels: &TunnelChannels,
+    port: u16,
+    addr: SocketAddr,
+    mut remote: T,
+    hostname: String,
+    head: Vec<u8>,
+    http: bool,
+) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let key = TunnelKey {
+        port,
+        hostname: Some(hostname),
+    };
+
+    let route = match tunnel::pick_route(&key).await {
+        Some(route) => route,
+        None => {
+            if http {
+                remote.write_all(NOT_FOUND_RESPONSE).await?;
+            }
+
+            return Ok(());
+        }
+    };
+
+    let _guard = match route.limiter.acquire(addr.ip()) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("Rejected {} on port {}: {}", addr, port, e);
+            if http {
+                remote.write_all(UNAVAILABLE_RESPONSE).await?;
+            }
+
+            return Ok(());
+        }
+    };
+
+    match tunnel::request_tunnel(tunnel_channels, &key, route).await {
+        Some((tunnel, route)) => {
+            let mut tunnel = tunnel.framed(&PortType::Tcp);
+            tunnel.write_all(&head).await?;
+            tunnel.flush().await?;
+            let options = CopyOptions {
+                idle_timeout: route.port.idle_timeout(),
+                limits: route.limits,
+                on_idle: Some(metrics::record_idle),
+            };
+            tunnel.copy_bidirectional(remote, &options).await?;
+        }
+        None => {
+            eprintln!("Tunnel timed out (port {}), client didn't dial back", port);
+            if http {
+                remote.write_all(BAD_GATEWAY_RESPONSE).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads request head and returns its Host (without port) together with
+/// everything read so far, which still has to be sent through the tunnel.
+/// Only first request on the connection is inspected, so keep-alive
+/// connections stay with the hostname they started with.
+pub async fn read_http_host<T>(stream: &mut T) -> Result<(String, Vec<u8>)>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut head = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            color_eyre::eyre::bail!("Connection closed before request head");
+        }
+
+        head.extend_from_slice(&buf[..n]);
+        if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            let hostname = parse_host(&head[..end])?;
+            return Ok((hostname, head));
+        }
+
+        if head.len() > MAX_HEAD_SIZE {
+            color_eyre::eyre::bail!("Request head too large");
+        }
+    }
+}
+
+fn parse_host(head: &[u8]) -> Result<String> {
+    let head = std::str::from_utf8(head)?;
+    for line in head.split("\r\n").skip(1) {
+        let (name, value) = match line.split_once(':') {
+            Some(header) => header,
+            None => continue,
+        };
+
+        if name.trim().eq_ignore_ascii_case("host") {
+            let host = value.trim();
+            let host = match host.rsplit_once(':') {
+                Some((host, port)) if port.parse::<u16>().is_ok() => host,
+                _ => host,
+            };
+
+            return Ok(host.to_lowercase());
+        }
+    }
+
+    color_eyre::eyre::bail!("Missing Host header")
+}
+
+/// Reads TLS ClientHello and returns its server name (SNI) together with
+/// everything read so far. Nothing is decrypted, the raw stream is passed
+/// through to the client.
+pub async fn read_tls_sni<T>(stream: &mut T) -> Result<(String, Vec<u8>)>
+where
+    T: AsyncRead + Unpin,
+{
+    let mut head = vec![0u8; 5];
+    stream.read_exact(&mut head).await?;
+    if head[0] != TLS_HANDSHAKE {
+        color_eyre::eyre::bail!("Not a TLS handshake");
+    }
+
+    let record_len = u16::from_be_bytes([head[3], head[4]]) as usize;
+    if record_len > MAX_HEAD_SIZE {
+        color_eyre::eyre::bail!("TLS record too large");
+    }
+
+    head.resize(5 + record_len, 0);
+    stream.read_exact(&mut head[5..]).await?;
+
+    let hostname = parse_sni(&head[5..])
+        .ok_or_else(|| color_eyre::eyre::eyre!("Missing SNI in ClientHello"))?;
+
+    Ok((hostname.to_lowercase(), head))
+}
+
+fn parse_sni(hello: &[u8]) -> Option<String> {
+    if *hello.first()? != TLS_CLIENT_HELLO {
+        return None;
+    }
+
+    // handshake header (4), client version (2), random (32)
+    let mut pos = 38;
+    let session_id_len = *hello.get(pos)? as usize;
+    pos += 1 + session_id_len;
+
+    let cipher_suites_len = read_u16(hello, pos)? as usize;
+    pos += 2 + cipher_suites_len;
+
+    let compression_len = *hello.get(pos)? as usize;
+    pos += 1 + compression_len;
+
+    let extensions_end = pos + 2 + read_u16(hello, pos)? as usize;
+    pos += 2;
+
+    while pos + 4 <= extensions_end {
+        let extension_type = read_u16(hello, pos)?;
+        let extension_len = read_u16(hello, pos + 2)? as usize;
+        pos += 4;
+
+        if extension_type == TLS_SNI_EXTENSION {
+            // server name list length (2), name type (1), name length (2)
+            let name_len = read_u16(hello, pos + 3)? as usize;
+            let name = hello.get(pos + 5..pos + 5 + name_len)?;
+
+            return String::from_utf8(name.to_vec()).ok();
+        }
+
+        pos += extension_len;
+    }
+
+    None
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*buf.get(pos)?, *buf.get(pos + 1)?]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal ClientHello (without record header) with given extensions
+    fn client_hello(extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0); // session id
+        body.extend_from_slice(&[0, 2, 0x13, 0x01]); // cipher suites
+        body.extend_from_slice(&[1, 0]); // compression methods
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(extensions);
+
+        let mut hello = vec![TLS_CLIENT_HELLO, 0];
+        hello.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&body);
+        hello
+    }
+
+    fn sni_extension(name: &str) -> Vec<u8> {
+        let mut list = vec![0];
+        list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        list.extend_from_slice(name.as_bytes());
+
+        let mut extension = TLS_SNI_EXTENSION.to_be_bytes().to_vec();
+        extension.extend_from_slice(&(list.len() as u16 + 2).to_be_bytes());
+        extension.extend_from_slice(&(list.len() as u16).to_be_bytes());
+        extension.extend_from_slice(&list);
+        extension
+    }
+
+    #[test]
+    fn host_header_is_found() {
+        let head = b"GET / HTTP/1.1\r\nUser-Agent: test\r\nHOST: Example.COM";
+        assert_eq!(parse_host(head).unwrap(), "example.com");
+    }
+
+    #[test]
+    fn host_port_is_stripped() {
+        let head = b"GET / HTTP/1.1\r\nHost: example.com:8080";
+        assert_eq!(parse_host(head).unwrap(), "example.com");
+
+        let head = b"GET / HTTP/1.1\r\nHost: [::1]:8080";
+        assert_eq!(parse_host(head).unwrap(), "[::1]");
+    }
+
+    #[test]
+    fn missing_host_is_rejected() {
+        assert!(parse_host(b"GET / HTTP/1.1\r\nAccept: */*").is_err());
+        // request line isn't a header even if it looks like one
+        assert!(parse_host(b"Host: example.com").is_err());
+    }
+
+    #[test]
+    fn sni_is_found_after_other_extensions() {
+        // supported versions before the server name
+        let mut extensions = vec![0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04];
+        extensions.extend_from_slice(&sni_extension("example.com"));
+
+        let hello = client_hello(&extensions);
+        assert_eq!(parse_sni(&hello).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn missing_or_truncated_sni_is_none() {
+        assert_eq!(parse_sni(&client_hello(&[])), None);
+
+        let hello = client_hello(&sni_extension("example.com"));
+        assert_eq!(parse_sni(&hello[..hello.len() - 4]), None);
+        assert_eq!(parse_sni(&[0x02]), None);
+    }
+
+    #[tokio::test]
+    async fn sni_is_read_from_record() {
+        let hello = client_hello(&sni_extension("Example.com"));
+        let mut record = vec![TLS_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        record.extend_from_slice(&hello);
+
+        let (hostname, head) = read_tls_sni(&mut &record[..]).await.unwrap();
+        assert_eq!(hostname, "example.com");
+        assert_eq!(head, record);
+    }
+}
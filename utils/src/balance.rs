@@ -0,0 +1,126 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Seconds backend which refused connection is tried only after the others
+const BACKEND_RETRY: u64 = 10;
+
+/// How new connections of a port are spread over its local backends
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+    Random,
+}
+
+/// Ports with the same backends share their balancer, so connection counts
+/// include connections of all of them
+static BALANCERS: Mutex<BTreeMap<Vec<String>, Arc<Balancer>>> = Mutex::new(BTreeMap::new());
+
+/// Round robin position, open connections and failures of `host:port`
+/// backends
+pub struct Balancer {
+    backends: Vec<String>,
+    next: AtomicUsize,
+    active: Vec<AtomicUsize>,
+    failed: Vec<Mutex<Option<Instant>>>,
+}
+
+/// Counts connection to a backend as open while it's alive
+pub struct BackendGuard {
+    balancer: Arc<Balancer>,
+    index: usize,
+}
+
+impl Balancer {
+    pub fn get(backends: &[String]) -> Arc<Self> {
+        let mut balancers = match BALANCERS.lock() {
+            Ok(balancers) => balancers,
+            Err(_) => return Arc::new(Self::new(backends)),
+        };
+
+        balancers
+            .entry(backends.to_vec())
+            .or_insert_with(|| Arc::new(Self::new(backends)))
+            .clone()
+    }
+
+    fn new(backends: &[String]) -> Self {
+        Self {
+            backends: backends.to_vec(),
+            next: AtomicUsize::new(0),
+            active: backends.iter().map(|_| AtomicUsize::new(0)).collect(),
+            failed: backends.iter().map(|_| Mutex::new(None)).collect(),
+        }
+    }
+
+    pub fn backend(&self, index: usize) -> &str {
+        &self.backends[index]
+    }
+
+    /// Indexes of backends in order they should be tried, the one picked by
+    /// `balance` first and the others after it in case it's down. Recently
+    /// failed backends go last.
+    pub fn order(&self, balance: Balance) -> Vec<usize> {
+        let len = self.backends.len();
+        if len == 0 {
+            return vec![];
+        }
+
+        let first = match balance {
+            Balance::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % len,
+            Balance::LeastConnections => (0..len)
+                .filter(|&i| !self.is_failed(i))
+                .min_by_key(|&i| self.active[i].load(Ordering::Relaxed))
+                .unwrap_or(0),
+            Balance::Random => rand::thread_rng().gen_range(0..len),
+        };
+
+        let mut order: Vec<usize> = (0..len).map(|i| (first + i) % len).collect();
+        order.sort_by_key(|&i| self.is_failed(i));
+        order
+    }
+
+    /// Marks backend which couldn't be connected to
+    pub fn fail(&self, index: usize) {
+        if let Ok(mut failed) = self.failed[index].lock() {
+            failed.replace(Instant::now());
+        }
+    }
+
+    fn is_failed(&self, index: usize) -> bool {
+        self.failed[index]
+            .lock()
+            .map(|failed| {
+                failed.is_some_and(|at| at.elapsed() < Duration::from_secs(BACKEND_RETRY))
+            })
+            .unwrap_or(false)
+    }
+
+    /// Counts open connection to the backend until the guard is dropped
+    pub fn track(self: &Arc<Self>, index: usize) -> BackendGuard {
+        if let Ok(mut failed) = self.failed[index].lock() {
+            failed.take();
+        }
+
+        self.active[index].fetch_add(1, Ordering::Relaxed);
+        BackendGuard {
+            balancer: self.clone(),
+            index,
+        }
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.balancer.active[self.index].fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Token bucket shared by all connections it limits. Holds at most one
+/// second worth of tokens, traffic over the limit goes into debt which is
+/// slept off before sending more.
+pub struct Bucket {
+    pub name: String,
+    rate: u64,
+    state: Mutex<BucketState>,
+    bytes: AtomicU64,
+    throttled_us: AtomicU64,
+}
+
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    /// `rate` in bytes per second, `name` identifies the bucket in metrics
+    pub fn new(name: String, rate: u64) -> Arc<Self> {
+        Arc::new(Self {
+            name,
+            rate: rate.max(1),
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+            bytes: AtomicU64::new(0),
+            throttled_us: AtomicU64::new(0),
+        })
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Bytes sent through the bucket so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Total time connections were held back by the bucket
+    pub fn throttled(&self) -> Duration {
+        Duration::from_micros(self.throttled_us.load(Ordering::Relaxed))
+    }
+
+    /// True while bucket is in debt, so connections are being held back
+    pub fn is_throttling(&self) -> bool {
+        self.state
+            .lock()
+            .map(|s| s.tokens + s.last.elapsed().as_secs_f64() * (self.rate as f64) < 0.0)
+            .unwrap_or(false)
+    }
+
+    /// Takes `n` tokens and returns how long sender has to wait
+    fn take(&self, n: usize) -> Duration {
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return Duration::ZERO,
+        };
+
+        let now = Instant::now();
+        let refill = now.duration_since(state.last).as_secs_f64() * self.rate as f64;
+        state.tokens = (state.tokens + refill).min(self.rate as f64) - n as f64;
+        state.last = now;
+
+        if state.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+
+        let wait = Duration::from_secs_f64(-state.tokens / self.rate as f64);
+        self.throttled_us
+            .fetch_add(wait.as_micros() as u64, Ordering::Relaxed);
+        wait
+    }
+}
+
+/// Buckets applied to one connection (e.g. global, client session and port),
+/// traffic of both directions is counted together
+#[derive(Clone, Default)]
+pub struct Limits(Vec<Arc<Bucket>>);
+
+impl Limits {
+    pub fn with(mut self, bucket: Option<Arc<Bucket>>) -> Self {
+        if let Some(bucket) = bucket {
+            self.0.push(bucket);
+        }
+
+        self
+    }
+
+    /// Accounts `n` bytes in every bucket and waits for the slowest one
+    pub async fn consume(&self, n: usize) {
+        let wait = self
+            .0
+            .iter()
+            .map(|bucket| bucket.take(n))
+            .max()
+            .unwrap_or_default();
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    future::poll_fn,
+    io,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    task::Poll,
+};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Buffers kept for reuse, the rest is freed when returned
+const MAX_POOLED: usize = 256;
+
+static BUFFER_SIZE: AtomicUsize = AtomicUsize::new(crate::BUFFER_SIZE);
+static POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+/// Datagram buffers have to fit any datagram, their size isn't configurable
+static DATAGRAM_POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+/// Sets size of pooled buffers, buffers of the old size are dropped
+pub fn set_buffer_size(size: usize) {
+    BUFFER_SIZE.store(size.max(1), Ordering::Relaxed);
+    if let Ok(mut pool) = POOL.lock() {
+        pool.clear();
+    }
+}
+
+pub fn buffer_size() -> usize {
+    BUFFER_SIZE.load(Ordering::Relaxed)
+}
+
+/// Takes buffer from the pool (or allocates new one)
+pub fn get() -> PooledBuf {
+    take(&POOL, buffer_size)
+}
+
+/// Takes buffer fitting any datagram from the pool (or allocates new one)
+pub fn get_datagram() -> PooledBuf {
+    take(&DATAGRAM_POOL, datagram_size)
+}
+
+fn datagram_size() -> usize {
+    crate::BUFFER_SIZE
+}
+
+fn take(pool: &'static Mutex<Vec<Vec<u8>>>, size: fn() -> usize) -> PooledBuf {
+    let buf = pool
+        .lock()
+        .ok()
+        .and_then(|mut pool| pool.pop())
+        .filter(|buf| buf.len() == size())
+        .unwrap_or_else(|| vec![0; size()]);
+
+    PooledBuf { buf, pool, size }
+}
+
+/// Reads one datagram into pooled buffer. Buffer is taken only for the poll,
+/// so flows waiting for their next datagram don't hold any.
+pub async fn read_datagram<R>(reader: &mut R) -> io::Result<(PooledBuf, usize)>
+where
+    R: AsyncRead + Unpin,
+{
+    poll_fn(|cx| {
+        let mut buf = get_datagram();
+        let mut read = ReadBuf::new(&mut buf);
+        match Pin::new(&mut *reader).poll_read(cx, &mut read) {
+            Poll::Ready(Ok(())) => {
+                let n = read.filled().len();
+                Poll::Ready(Ok((buf, n)))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Buffer which goes back to its pool when dropped
+pub struct PooledBuf {
+    buf: Vec<u8>,
+    pool: &'static Mutex<Vec<Vec<u8>>>,
+    size: fn() -> usize,
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if self.buf.len() != (self.size)() {
+            return;
+        }
+
+        if let Ok(mut pool) = self.pool.lock() {
+            if pool.len() < MAX_POOLED {
+                pool.push(std::mem::take(&mut self.buf));
+            }
+        }
+    }
+}
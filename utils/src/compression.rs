@@ -0,0 +1,93 @@
+use async_compression::tokio::{
+    bufread::{Lz4Decoder, ZstdDecoder},
+    write::{Lz4Encoder, ZstdEncoder},
+};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader, ReadBuf};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum Compression {
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    /// Id sent in the tunnel preamble
+    pub fn id(&self) -> u8 {
+        match self {
+            Compression::Zstd => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self> {
+        match id {
+            1 => Ok(Compression::Zstd),
+            2 => Ok(Compression::Lz4),
+            _ => Err(color_eyre::eyre::eyre!("Unsupported compression: {}", id)),
+        }
+    }
+}
+
+/// Byte stream compressed in both directions. Every flush ends a compressed
+/// block so interactive protocols aren't delayed, shutdown finishes the
+/// stream which the other side reads as EOF.
+pub struct Compressed {
+    reader: Pin<Box<dyn AsyncRead + Send + Sync>>,
+    writer: Pin<Box<dyn AsyncWrite + Send + Sync>>,
+}
+
+impl Compressed {
+    pub fn new<S>(inner: S, compression: Compression) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Sync + 'static,
+    {
+        let (read, write) = tokio::io::split(inner);
+        let read = BufReader::new(read);
+
+        match compression {
+            Compression::Zstd => Self {
+                reader: Box::pin(ZstdDecoder::new(read)),
+                writer: Box::pin(ZstdEncoder::new(write)),
+            },
+            Compression::Lz4 => Self {
+                reader: Box::pin(Lz4Decoder::new(read)),
+                writer: Box::pin(Lz4Encoder::new(write)),
+            },
+        }
+    }
+}
+
+impl AsyncRead for Compressed {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.reader.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Compressed {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.writer.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.writer.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.writer.as_mut().poll_shutdown(cx)
+    }
+}
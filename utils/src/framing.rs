@@ -0,0 +1,705 @@
+use crate::{
+    buffer_pool::{self, PooledBuf},
+    BUFFER_SIZE,
+};
+use color_eyre::Result;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+
+// Every UDP tunnel packet starts with one of these
+const FRAME_DATAGRAM: u8 = 0;
+const FRAME_DATA: u8 = 1;
+const FRAME_ACK: u8 = 2;
+const FRAME_FIN: u8 = 3;
+const FRAME_HANDSHAKE: u8 = 4;
+const FRAME_PING: u8 = 5;
+
+/// Length prefix of UDP-over-TCP datagrams
+const LENGTH_SIZE: usize = 2;
+
+/// Kind byte + u64 sequence number
+const HEADER_SIZE: usize = 9;
+
+/// Payload of one reliable segment, small enough to not get fragmented
+const SEGMENT_SIZE: usize = 1200;
+
+/// Max segments in flight (and max segments buffered out of order)
+const WINDOW: u64 = 256;
+
+const RETRANSMIT_TIMEOUT: u64 = 200;
+const MAX_BACKOFF: u32 = 4;
+const HANDSHAKE_ATTEMPTS: u32 = 10;
+
+/// In order bytes buffered for the app before segments stop being acked
+const READY_SIZE: usize = WINDOW as usize * SEGMENT_SIZE;
+
+/// Seconds between pings of reliable tunnel, peer is gone after PEER_TIMEOUT
+/// seconds without any frame
+const KEEPALIVE_INTERVAL: u64 = 5;
+const PEER_TIMEOUT: u64 = 30;
+
+/// First datagram of every raw UDP tunnel:
+///
+/// | u8 kind (4) | u16 port | u64 code | u64 connection id | u64 request id | hostname (rest, optional) |
+///
+/// Request id is the one server asked for the tunnel with.
+///
+/// Server answers with `| u8 kind (4) | u64 connection id |`. Kind byte keeps
+/// it apart from data frames, so a late duplicate ack is just skipped and
+/// a retransmitted handshake is acknowledged again.
+#[derive(Debug, Clone)]
+pub struct UdpHandshake {
+    pub port: u16,
+    pub code: u64,
+    pub connection_id: u64,
+    pub request_id: u64,
+    pub hostname: Option<String>,
+}
+
+impl UdpHandshake {
+    const SIZE: usize = 27;
+    pub const MAX_SIZE: usize = Self::SIZE + u8::MAX as usize;
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+        bytes.push(FRAME_HANDSHAKE);
+        bytes.extend_from_slice(&self.port.to_be_bytes());
+        bytes.extend_from_slice(&self.code.to_be_bytes());
+        bytes.extend_from_slice(&self.connection_id.to_be_bytes());
+        bytes.extend_from_slice(&self.request_id.to_be_bytes());
+
+        if let Some(hostname) = &self.hostname {
+            if hostname.len() > u8::MAX as usize {
+                color_eyre::eyre::bail!("Hostname too long: {}", hostname);
+            }
+
+            bytes.extend_from_slice(hostname.as_bytes());
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        if data.len() < Self::SIZE || data.len() > Self::MAX_SIZE || data[0] != FRAME_HANDSHAKE {
+            color_eyre::eyre::bail!("Invalid UDP handshake ({} bytes)", data.len());
+        }
+
+        let hostname = if data.len() > Self::SIZE {
+            Some(String::from_utf8(data[Self::SIZE..].to_vec())?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            port: u16::from_be_bytes(data[1..3].try_into()?),
+            code: u64::from_be_bytes(data[3..11].try_into()?),
+            connection_id: u64::from_be_bytes(data[11..19].try_into()?),
+            request_id: u64::from_be_bytes(data[19..27].try_into()?),
+            hostname,
+        })
+    }
+
+    /// Client side, sends handshake until server acknowledges it
+    pub async fn send<S>(&self, stream: &mut S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let bytes = self.encode()?;
+        let mut buf = [0u8; HEADER_SIZE];
+
+        for _ in 0..HANDSHAKE_ATTEMPTS {
+            stream.write_all(&bytes).await?;
+            stream.flush().await?;
+
+            let timeout = Duration::from_millis(RETRANSMIT_TIMEOUT);
+            if let Ok(res) = tokio::time::timeout(timeout, stream.read(&mut buf)).await {
+                let n = res?;
+                if n == HEADER_SIZE
+                    && buf[0] == FRAME_HANDSHAKE
+                    && buf[1..] == self.connection_id.to_be_bytes()
+                {
+                    return Ok(());
+                }
+            }
+        }
+
+        color_eyre::eyre::bail!("UDP handshake not acknowledged (port {})", self.port)
+    }
+
+    /// Server side, reads first datagram of the flow. Buffer fits any
+    /// datagram, flow stream can't read it partially.
+    pub async fn receive<S>(stream: &mut S) -> Result<Self>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        let n = stream.read(&mut buf).await?;
+        Self::decode(&buf[..n])
+    }
+
+    /// Server side, acknowledges received handshake and returns the flow
+    /// acknowledging its retransmits
+    pub async fn accept<S>(&self, mut stream: S) -> Result<AcceptedFlow<S>>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let ack = self.ack();
+        stream.write_all(&ack).await?;
+        stream.flush().await?;
+
+        Ok(AcceptedFlow {
+            inner: stream,
+            connection_id: self.connection_id,
+            ack,
+        })
+    }
+
+    fn ack(&self) -> [u8; HEADER_SIZE] {
+        let mut ack = [0u8; HEADER_SIZE];
+        ack[0] = FRAME_HANDSHAKE;
+        ack[1..].copy_from_slice(&self.connection_id.to_be_bytes());
+        ack
+    }
+}
+
+/// Server side of raw UDP tunnel after the handshake. Client sends it again
+/// until it sees the ack, so when the ack got lost the retransmit is
+/// acknowledged again instead of being passed on with the data.
+pub struct AcceptedFlow<S> {
+    inner: S,
+    connection_id: u64,
+    ack: [u8; HEADER_SIZE],
+}
+
+impl<S> AsyncRead for AcceptedFlow<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            let filled = buf.filled().len();
+            match Pin::new(&mut this.inner).poll_read(cx, buf) {
+                Poll::Ready(Ok(())) => {}
+                other => return other,
+            }
+
+            let datagram = &buf.filled()[filled..];
+            let retransmit = datagram.first() == Some(&FRAME_HANDSHAKE)
+                && UdpHandshake::decode(datagram)
+                    .is_ok_and(|h| h.connection_id == this.connection_id);
+            if !retransmit {
+                return Poll::Ready(Ok(()));
+            }
+
+            // best effort like the first ack, client sends handshake again
+            buf.set_filled(filled);
+            let _ = Pin::new(&mut this.inner).poll_write(cx, &this.ack);
+        }
+    }
+}
+
+impl<S> AsyncWrite for AcceptedFlow<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// UDP-over-UDP framing. Every write is sent as one datagram frame and every
+/// read returns payload of one frame, so packet boundaries are preserved.
+pub struct DatagramFramed<S> {
+    inner: S,
+}
+
+impl<S> DatagramFramed<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+impl<S> AsyncRead for DatagramFramed<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            // pooled only for the poll, frame is copied out right away
+            let mut frame_buf = buffer_pool::get_datagram();
+            let mut frame = ReadBuf::new(&mut frame_buf);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut frame) {
+                Poll::Ready(Ok(())) => {}
+                other => return other,
+            }
+
+            let frame = frame.filled();
+            if frame.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+
+            // anything else isn't meant for datagram tunnel, skip it
+            if frame[0] == FRAME_DATAGRAM {
+                let n = buf.remaining().min(frame.len() - 1);
+                buf.put_slice(&frame[1..n + 1]);
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for DatagramFramed<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut frame = Vec::with_capacity(buf.len() + 1);
+        frame.push(FRAME_DATAGRAM);
+        frame.extend_from_slice(buf);
+
+        match Pin::new(&mut self.get_mut().inner).poll_write(cx, &frame) {
+            Poll::Ready(Ok(_)) => Poll::Ready(Ok(buf.len())),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// UDP-over-TCP framing. Every datagram is prefixed with its u16 length, so
+/// datagrams coalesced or split by the byte stream are delivered intact.
+pub struct LengthFramed<S> {
+    inner: S,
+    /// Pooled, held only while part of a datagram is buffered
+    read_buf: Option<PooledBuf>,
+    read_filled: usize,
+    write_buf: Vec<u8>,
+}
+
+impl<S> LengthFramed<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            read_buf: None,
+            read_filled: 0,
+            write_buf: Vec::new(),
+        }
+    }
+}
+
+impl<S> LengthFramed<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.write_buf) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => {
+                    self.write_buf.drain(..n);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncRead for LengthFramed<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let read_buf = this.read_buf.get_or_insert_with(buffer_pool::get_datagram);
+
+        loop {
+            if this.read_filled >= LENGTH_SIZE {
+                let len = u16::from_be_bytes([read_buf[0], read_buf[1]]) as usize;
+                let end = LENGTH_SIZE + len;
+                if end > read_buf.len() {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "datagram too large",
+                    )));
+                }
+
+                if this.read_filled >= end {
+                    let n = buf.remaining().min(len);
+                    buf.put_slice(&read_buf[LENGTH_SIZE..LENGTH_SIZE + n]);
+
+                    read_buf.copy_within(end..this.read_filled, 0);
+                    this.read_filled -= end;
+                    if this.read_filled == 0 {
+                        this.read_buf = None;
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            let mut read = ReadBuf::new(&mut read_buf[this.read_filled..]);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut read) {
+                Poll::Ready(Ok(())) => {}
+                other => {
+                    if this.read_filled == 0 {
+                        this.read_buf = None;
+                    }
+                    return other;
+                }
+            }
+
+            let n = read.filled().len();
+            if n == 0 {
+                if this.read_filled > 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+
+                this.read_buf = None;
+                return Poll::Ready(Ok(()));
+            }
+            this.read_filled += n;
+        }
+    }
+}
+
+impl<S> AsyncWrite for LengthFramed<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // has to fit pooled read buffer on the other side
+        if LENGTH_SIZE + buf.len() > BUFFER_SIZE {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram too large",
+            )));
+        }
+
+        // previous datagram has to be out first, frames can't interleave
+        match this.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        this.write_buf
+            .extend_from_slice(&(buf.len() as u16).to_be_bytes());
+        this.write_buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
+
+/// TCP-over-UDP framing. Spawns worker which splits the byte stream into
+/// sequenced segments, acks received ones and retransmits lost ones.
+/// Returned stream is the ordered, reliable side of the tunnel.
+pub fn reliable<S>(transport: S) -> DuplexStream
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (stream, worker_stream) = tokio::io::duplex(BUFFER_SIZE);
+
+    tokio::spawn(async move {
+        if let Err(e) = reliable_worker(transport, worker_stream).await {
+            eprintln!("Reliable tunnel error: {}", e);
+        }
+    });
+
+    stream
+}
+
+struct Segment {
+    frame: Vec<u8>,
+    sent: Instant,
+    retransmits: u32,
+}
+
+async fn reliable_worker<S>(transport: S, stream: DuplexStream) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut net_read, mut net_write) = tokio::io::split(transport);
+    let (mut app_read, mut app_write) = tokio::io::split(stream);
+
+    let mut next_seq: u64 = 0;
+    let mut unacked: BTreeMap<u64, Segment> = BTreeMap::new();
+    let mut fin_sent = false;
+
+    // None marks FIN
+    let mut expected: u64 = 0;
+    let mut received: BTreeMap<u64, Option<Vec<u8>>> = BTreeMap::new();
+    let mut fin_received = false;
+
+    // in order data waiting for the app, written only when it has room so
+    // a slow reader never holds up acks and retransmissions
+    let mut ready: VecDeque<u8> = VecDeque::new();
+    let mut app_closed = false;
+    let mut ack_needed = false;
+
+    let mut app_buf = vec![0u8; SEGMENT_SIZE];
+    let mut retransmit = tokio::time::interval(Duration::from_millis(RETRANSMIT_TIMEOUT));
+    let mut keepalive = tokio::time::interval(Duration::from_secs(KEEPALIVE_INTERVAL));
+    let mut last_received = Instant::now();
+
+    loop {
+        // segments are acked only once they fit into ready, so the peer
+        // backs off while the app doesn't read
+        while ready.len() < READY_SIZE {
+            match received.remove(&expected) {
+                Some(Some(payload)) => ready.extend(payload),
+                Some(None) => fin_received = true,
+                None => break,
+            }
+            expected += 1;
+            ack_needed = true;
+        }
+
+        if ack_needed {
+            // cumulative, also resent for duplicates in case ack was lost
+            let mut ack = [0u8; HEADER_SIZE];
+            ack[0] = FRAME_ACK;
+            ack[1..].copy_from_slice(&expected.to_be_bytes());
+            net_write.write_all(&ack).await?;
+            ack_needed = false;
+        }
+
+        if fin_received && ready.is_empty() && !app_closed {
+            app_write.shutdown().await?;
+            app_closed = true;
+        }
+
+        if fin_sent && unacked.is_empty() && app_closed {
+            break;
+        }
+
+        let can_send = !fin_sent && (unacked.len() as u64) < WINDOW;
+
+        tokio::select! {
+            res = app_read.read(&mut app_buf), if can_send => {
+                let n = res?;
+
+                let mut frame = Vec::with_capacity(HEADER_SIZE + n);
+                if n == 0 {
+                    frame.push(FRAME_FIN);
+                    fin_sent = true;
+                } else {
+                    frame.push(FRAME_DATA);
+                }
+                frame.extend_from_slice(&next_seq.to_be_bytes());
+                frame.extend_from_slice(&app_buf[..n]);
+
+                net_write.write_all(&frame).await?;
+                unacked.insert(next_seq, Segment { frame, sent: Instant::now(), retransmits: 0 });
+                next_seq += 1;
+            }
+            res = app_write.write(ready.as_slices().0), if !ready.is_empty() => {
+                let n = res?;
+                ready.drain(..n);
+            }
+            res = buffer_pool::read_datagram(&mut net_read) => {
+                let (net_buf, n) = res?;
+                if n == 0 {
+                    break;
+                }
+
+                let frame = &net_buf[..n];
+                if frame.len() < HEADER_SIZE {
+                    continue;
+                }
+                last_received = Instant::now();
+                let seq = u64::from_be_bytes(frame[1..HEADER_SIZE].try_into()?);
+
+                match frame[0] {
+                    FRAME_ACK => {
+                        let before = unacked.len();
+                        unacked.retain(|s, _| *s >= seq);
+
+                        // peer's window moves again, no reason to keep backing off
+                        if unacked.len() != before {
+                            unacked.values_mut().for_each(|s| s.retransmits = 0);
+                        }
+                    }
+                    FRAME_DATA | FRAME_FIN => {
+                        if seq >= expected && seq < expected + WINDOW {
+                            let payload = if frame[0] == FRAME_DATA {
+                                Some(frame[HEADER_SIZE..].to_vec())
+                            } else {
+                                None
+                            };
+                            received.entry(seq).or_insert(payload);
+                        }
+
+                        ack_needed = true;
+                    }
+                    _ => {}
+                }
+            }
+            _ = retransmit.tick() => {
+                for segment in unacked.values_mut() {
+                    // backs off while peer's window is closed (its app doesn't read)
+                    let timeout = RETRANSMIT_TIMEOUT << segment.retransmits.min(MAX_BACKOFF);
+                    if segment.sent.elapsed() < Duration::from_millis(timeout) {
+                        continue;
+                    }
+
+                    net_write.write_all(&segment.frame).await?;
+                    segment.sent = Instant::now();
+                    segment.retransmits += 1;
+                }
+            }
+            _ = keepalive.tick() => {
+                if last_received.elapsed() >= Duration::from_secs(PEER_TIMEOUT) {
+                    // peer already got everything and left, only our FIN ack got lost
+                    if fin_received {
+                        return Ok(());
+                    }
+
+                    color_eyre::eyre::bail!("Peer stopped responding");
+                }
+
+                // keeps idle connection (and NAT mappings on the way) alive
+                let mut ping = [0u8; HEADER_SIZE];
+                ping[0] = FRAME_PING;
+                net_write.write_all(&ping).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UdpSocket;
+    use udpflow::UdpStreamRemote;
+
+    /// Two reliable streams talking to each other over loopback UDP
+    async fn reliable_pair() -> (DuplexStream, DuplexStream) {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+
+        (
+            reliable(UdpStreamRemote::new(a, b_addr)),
+            reliable(UdpStreamRemote::new(b, a_addr)),
+        )
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn transfers_stream_in_order() {
+        let (mut a, mut b) = reliable_pair().await;
+        let data = pattern(1 << 20);
+
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            a.write_all(&sent).await.unwrap();
+            a.shutdown().await.unwrap();
+            a
+        });
+
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).await.unwrap();
+        assert!(received == data);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn slow_reader_does_not_stall_other_direction() {
+        let (a, mut b) = reliable_pair().await;
+        let data = pattern(1 << 20);
+
+        // fills b's window while its app doesn't read
+        let (mut a_read, mut a_write) = tokio::io::split(a);
+        let sent = data.clone();
+        tokio::spawn(async move { a_write.write_all(&sent).await });
+        let reply = tokio::spawn(async move {
+            let mut reply = [0u8; 5];
+            a_read.read_exact(&mut reply).await.unwrap();
+            reply
+        });
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        b.write_all(b"hello").await.unwrap();
+        let reply = tokio::time::timeout(Duration::from_secs(2), reply)
+            .await
+            .expect("reply stuck behind unread data")
+            .unwrap();
+        assert_eq!(&reply, b"hello");
+
+        let mut received = vec![0u8; data.len()];
+        b.read_exact(&mut received).await.unwrap();
+        assert!(received == data);
+    }
+}
@@ -1,13 +1,69 @@
+use balance::Balance;
+use bandwidth::Limits;
 use color_eyre::Result;
+use compression::{Compressed, Compression};
+use framing::{AcceptedFlow, DatagramFramed, LengthFramed, UdpHandshake};
+use futures_util::FutureExt;
+use noise::NoiseStream;
+use proxy::Proxy;
+use quic::{DatagramFlow, QuicStream};
 use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
     net::TcpStream,
 };
 use udpflow::{UdpSocket, UdpStreamLocal, UdpStreamRemote};
+use websocket::WsStream;
+
+pub mod balance;
+pub mod bandwidth;
+pub mod buffer_pool;
+pub mod compression;
+pub mod framing;
+pub mod noise;
+pub mod proxy;
+pub mod quic;
+pub mod socks;
+#[cfg(target_os = "linux")]
+pub mod splice;
+pub mod websocket;
 
 pub const BUFFER_SIZE: usize = 65536;
 
+/// First read of every chunk goes to buffer this small, see `write_drained`
+pub const SMALL_BUFFER_SIZE: usize = 2048;
+
+/// Seconds without traffic after which UDP flow is closed (default)
+pub const UDP_IDLE_TIMEOUT: u64 = 60;
+
+/// Seconds of udpflow's own read timeout. It ends flows with EOF after 20s
+/// without incoming datagrams by default, flows are closed by `udp_idle_timeout`
+/// and reliable tunnel keepalives instead, so it's pushed out of their way.
+pub const UDPFLOW_TIMEOUT: u64 = 365 * 24 * 60 * 60;
+
+/// Max concurrent UDP flows per port (default)
+pub const MAX_UDP_FLOWS: usize = 1024;
+
+/// Seconds server waits for the client to dial back with a tunnel (default)
+pub const TUNNEL_TIMEOUT: u64 = 1;
+
+/// Seconds client waits for connection to the local service (default)
+pub const CONNECT_TIMEOUT: u64 = 10;
+
+/// Kinds of local forward tunnels
+pub const FORWARD_TCP: u8 = 0;
+pub const FORWARD_UDP: u8 = 1;
+pub const FORWARD_SERVICE: u8 = 2;
+pub const SERVICE_TUNNEL: u8 = 3;
+pub const FORWARD_PUNCH: u8 = 4;
+pub const PUNCH_LISTEN: u8 = 5;
+
 impl ConnectorInfo {
     pub fn encode(&self) -> Result<Vec<u8>> {
         Ok(serde_json::to_vec(self)?)
@@ -21,6 +77,10 @@ impl ConnectorInfo {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectorInfo {
     pub ports: Vec<ConnectorPort>,
+
+    /// Grants permissions to expose services
+    #[serde(default)]
+    pub token: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,6 +91,127 @@ pub struct ConnectorPort {
 
     pub port_type: PortType,
     pub tunnel_type: PortType,
+
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub terminate_tls: bool,
+
+    #[serde(default = "default_udp_idle_timeout")]
+    pub udp_idle_timeout: u64,
+    #[serde(default = "default_max_udp_flows")]
+    pub max_udp_flows: usize,
+
+    /// Seconds, unset ones fall back to global settings and then to defaults
+    pub tunnel_timeout: Option<u64>,
+    pub connect_timeout: Option<u64>,
+    pub tcp_idle_timeout: Option<u64>,
+
+    /// Bytes per second for all connections of the port together (enforced by the server)
+    pub bandwidth_limit: Option<u64>,
+
+    /// Connection limits enforced by the server before dialing back, unset means unlimited
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    /// New connections per second
+    pub connection_rate: Option<u32>,
+    pub connection_rate_per_ip: Option<u32>,
+
+    pub compression: Option<Compression>,
+
+    /// Local and dynamic ports are listened on by the client and dialed by
+    /// the server, `remote_host` and `port_remote` are the target of local ones
+    #[serde(default)]
+    pub direction: Direction,
+    pub remote_host: Option<String>,
+
+    /// Name of the service exposed by service port, local port with it
+    /// connects to the service instead of `remote_host`
+    pub service: Option<String>,
+
+    /// Service traffic goes over direct hole punched path between the
+    /// clients when it can be opened, relayed by the server otherwise
+    #[serde(default)]
+    pub direct: bool,
+
+    /// Local `host:port` services connections are balanced between, just
+    /// `local_ip:port_local` without them
+    #[serde(default)]
+    pub backends: Vec<String>,
+    #[serde(default)]
+    pub balance: Balance,
+
+    /// Sessions registering the port with `pool` share it on the server,
+    /// otherwise the last one takes it over
+    #[serde(default)]
+    pub pool: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum Direction {
+    /// Server listens on `port_remote`, client dials the local service
+    #[default]
+    Remote,
+    /// Client listens on `port_local`, server dials the target
+    Local,
+    /// Client listens on `port_local` as SOCKS5 proxy, server dials
+    /// destinations of its requests
+    Dynamic,
+    /// Nothing listens, other clients reach the local service by its name
+    Service,
+}
+
+fn default_udp_idle_timeout() -> u64 {
+    UDP_IDLE_TIMEOUT
+}
+
+fn default_max_udp_flows() -> usize {
+    MAX_UDP_FLOWS
+}
+
+impl ConnectorPort {
+    /// Http and Tls ports share one listener on the server and are routed by hostname
+    pub fn is_host_routed(&self) -> bool {
+        matches!(self.port_type, PortType::Http | PortType::Tls)
+    }
+
+    pub fn backends(&self) -> Vec<String> {
+        if !self.backends.is_empty() {
+            return self.backends.clone();
+        }
+
+        match self.local_ip.contains(':') {
+            true => vec![format!("[{}]:{}", self.local_ip, self.port_local)],
+            false => vec![format!("{}:{}", self.local_ip, self.port_local)],
+        }
+    }
+
+    pub fn tunnel_timeout(&self) -> Duration {
+        Duration::from_secs(self.tunnel_timeout.unwrap_or(TUNNEL_TIMEOUT))
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout.unwrap_or(CONNECT_TIMEOUT))
+    }
+
+    /// Compression of the port's tunnels, only byte stream tunnels (not UDP
+    /// or QUIC datagrams) can be compressed
+    pub fn compression(&self) -> Option<Compression> {
+        match self.tunnel_type {
+            PortType::Tcp | PortType::Ws => self.compression,
+            PortType::Quic if self.port_type != PortType::Udp => self.compression,
+            _ => None,
+        }
+    }
+
+    /// Idle timeout of forwarded connections, UDP flows always expire while
+    /// TCP connections only when `tcp_idle_timeout` is set
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        if self.port_type == PortType::Udp {
+            Some(Duration::from_secs(self.udp_idle_timeout))
+        } else {
+            self.tcp_idle_timeout.map(Duration::from_secs)
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -38,74 +219,501 @@ pub struct ConnectorPort {
 pub enum PortType {
     Tcp,
     Udp,
+    Http,
+    Tls,
+    Ws,
+    Quic,
+}
+
+/// Address of the connector (lf-server) and how to reach it
+#[derive(Debug, Clone)]
+pub struct ConnectorAddr {
+    pub ip: String,
+    pub port: u16,
+    pub proxy: Option<Proxy>,
+    pub quic_port: Option<u16>,
+    /// SHA-256 of the server's QUIC certificate, pinned when set
+    pub quic_fingerprint: Option<String>,
+    pub punch_port: Option<u16>,
+    pub noise: bool,
+}
+
+impl ConnectorAddr {
+    /// Opens stream-based connection to the connector (Tcp or Ws transport)
+    pub async fn connect(&self, transport: &PortType) -> Result<MultiStream> {
+        let stream = match &self.proxy {
+            Some(proxy) => proxy.connect(&self.ip, self.port).await?,
+            None => TcpStream::connect((self.ip.as_str(), self.port)).await?,
+        };
+        stream.set_nodelay(true)?;
+
+        match transport {
+            PortType::Tcp => Ok(MultiStream::Tcp(stream)),
+            PortType::Ws => Ok(MultiStream::Ws(Box::new(
+                websocket::connect(stream, &self.ip, self.port).await?,
+            ))),
+            _ => Err(color_eyre::eyre::eyre!(
+                "Invalid connector transport: {:?}",
+                transport
+            )),
+        }
+    }
+
+    /// Sends port of the connection (0 is the control connection) and
+    /// connector code. With noise the code is only used as handshake key and
+    /// never sent, rest of the connection is encrypted.
+    pub async fn authenticate(
+        &self,
+        stream: MultiStream,
+        port: u16,
+        code: u64,
+    ) -> Result<MultiStream> {
+        let mut stream = if self.noise {
+            MultiStream::Noise(Box::new(noise::initiate(stream, code).await?))
+        } else {
+            stream
+        };
+
+        // one write, server tells it apart from WebSocket upgrade by one peek
+        let mut preamble = port.to_be_bytes().to_vec();
+        if !self.noise {
+            preamble.extend_from_slice(&code.to_be_bytes());
+        }
+        stream.write_all(&preamble).await?;
+
+        Ok(stream)
+    }
+}
+
+/// What the server connects local forward tunnel to
+#[derive(Debug, Clone)]
+pub enum ForwardTarget {
+    Tcp(String, u16),
+    /// SOCKS UDP relay, datagrams carry their destinations
+    Udp,
+    /// Service exposed by other client, relayed by the server
+    Service {
+        name: String,
+        token: String,
+    },
+    /// Dialed back by the client exposing the service for its consumer,
+    /// answers server's request `request_id`
+    ServiceTunnel {
+        name: String,
+        token: String,
+        request_id: u64,
+    },
+    /// Exchange of public UDP endpoints and certificate fingerprints with the
+    /// other side of the service, consumer starts it with `id` 0 and the
+    /// server gives it new id
+    Punch {
+        name: String,
+        token: String,
+        id: u64,
+        fingerprint: String,
+    },
+    /// Rendezvous requests for the exposed service, sent as their ids
+    PunchListen {
+        name: String,
+        token: String,
+    },
+}
+
+/// Server didn't open local forward, holds its SOCKS reply code
+#[derive(Debug)]
+pub struct ForwardRefused(pub u8);
+
+impl std::fmt::Display for ForwardRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Forward refused by server (reply {})", self.0)
+    }
+}
+
+impl std::error::Error for ForwardRefused {}
+
+/// Sets udpflow's global read timeout, has to be called before any UDP flow
+/// is created
+pub fn init_udpflow() {
+    udpflow::set_timeout(Duration::from_secs(UDPFLOW_TIMEOUT));
+}
+
+pub async fn write_hostname<T>(stream: &mut T, hostname: &str) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    if hostname.len() > u8::MAX as usize {
+        color_eyre::eyre::bail!("Hostname too long: {}", hostname);
+    }
+
+    stream.write_u8(hostname.len() as u8).await?;
+    stream.write_all(hostname.as_bytes()).await?;
+    Ok(())
+}
+
+pub async fn read_hostname<T>(stream: &mut T) -> Result<String>
+where
+    T: AsyncRead + Unpin,
+{
+    let len = stream.read_u8().await?;
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+
+    Ok(String::from_utf8(buf)?)
+}
+
+/// Per connection settings of `MultiStream::copy_bidirectional`
+#[derive(Clone, Default)]
+pub struct CopyOptions {
+    pub idle_timeout: Option<Duration>,
+    pub limits: Limits,
+    /// Called when the connection is closed by `idle_timeout` (e.g. to count it)
+    pub on_idle: Option<fn()>,
 }
 
 pub enum MultiStream {
     Tcp(TcpStream),
-    UdpLocal(UdpStreamLocal),
+    UdpLocal(AcceptedFlow<UdpStreamLocal>),
     UdpRemote(UdpStreamRemote),
+    Ws(Box<WsStream<TcpStream>>),
+    Quic(Box<QuicStream>),
+    QuicDatagram(Box<DatagramFlow>),
+    Datagrams(Box<DatagramFramed<MultiStream>>),
+    Reliable(DuplexStream),
+    LengthPrefixed(Box<LengthFramed<MultiStream>>),
+    Compressed(Box<Compressed>),
+    Noise(Box<NoiseStream<MultiStream>>),
 }
 
 impl MultiStream {
+    /// Opens tunnel answering server's request `request_id` for a new
+    /// connection to the port
     pub async fn connect_and_setup(
-        connector_ip: &str,
-        connector_port: u16,
-        port_type: PortType,
-        port: u16,
+        connector: &ConnectorAddr,
+        port: &ConnectorPort,
         code: u64,
+        request_id: u64,
     ) -> Result<Self> {
-        let mut bytes: Vec<u8> = vec![];
-        bytes.write_u16(port).await?;
-        bytes.write_u64(code).await?;
-
-        match port_type {
-            PortType::Tcp => {
-                let mut stream =
-                    TcpStream::connect(format!("{}:{}", connector_ip, connector_port)).await?;
+        // only shared ports are routed by hostname, others send it empty
+        let hostname = port.hostname.as_ref().filter(|_| port.is_host_routed());
 
-                stream.set_nodelay(true)?;
-                stream.write_all(&bytes).await?;
-                stream.flush().await?;
+        let mut bytes: Vec<u8> = vec![];
+        write_hostname(&mut bytes, hostname.map_or("", |h| h.as_str())).await?;
+        bytes.write_u64(request_id).await?;
+        if let Some(compression) = port.compression() {
+            bytes.write_u8(compression.id()).await?;
+        }
 
-                Ok(MultiStream::Tcp(stream))
-            }
+        match port.tunnel_type {
             PortType::Udp => {
                 let socket = UdpSocket::bind("0.0.0.0:0").await?;
                 let mut stream = UdpStreamRemote::new(
                     socket,
-                    format!("{}:{}", connector_ip, connector_port).parse()?,
+                    format!("{}:{}", connector.ip, connector.port).parse()?,
                 );
-                stream.write_all(&bytes).await?;
 
-                Ok(MultiStream::UdpRemote(stream))
+                let handshake = UdpHandshake {
+                    port: port.port_remote,
+                    code,
+                    connection_id: rand::random(),
+                    request_id,
+                    hostname: hostname.cloned(),
+                };
+                handshake.send(&mut stream).await?;
+
+                Ok(MultiStream::UdpRemote(stream).framed(&port.port_type))
+            }
+            // boxed, quinn futures are big and would bloat every tunnel task
+            PortType::Quic if port.port_type == PortType::Udp => {
+                Box::pin(Self::connect_quic_datagram(connector, port, code, &bytes)).await
+            }
+            _ => {
+                let stream = Box::pin(Self::open_stream(
+                    connector,
+                    &port.tunnel_type,
+                    port.port_remote,
+                    code,
+                    &bytes,
+                ))
+                .await?;
+
+                Ok(stream
+                    .compressed(port.compression())
+                    .framed(&port.port_type))
+            }
+        }
+    }
+
+    /// Opens tunnel of local forward port to `target` (or of exposed service
+    /// for its consumer). It's sent on port 0 like the client info, empty
+    /// info marks the forward. Fails with `ForwardRefused` if the server
+    /// couldn't reach the target.
+    pub async fn connect_forward(
+        connector: &ConnectorAddr,
+        port: &ConnectorPort,
+        code: u64,
+        target: &ForwardTarget,
+    ) -> Result<Self> {
+        let kind = match target {
+            ForwardTarget::Tcp(..) => FORWARD_TCP,
+            ForwardTarget::Udp => FORWARD_UDP,
+            ForwardTarget::Service { .. } => FORWARD_SERVICE,
+            ForwardTarget::ServiceTunnel { .. } => SERVICE_TUNNEL,
+            ForwardTarget::Punch { .. } => FORWARD_PUNCH,
+            ForwardTarget::PunchListen { .. } => PUNCH_LISTEN,
+        };
+
+        let mut bytes: Vec<u8> = vec![];
+        bytes.write_u16(0).await?;
+        bytes.write_u8(kind).await?;
+        match target {
+            ForwardTarget::Tcp(host, target_port) => {
+                bytes
+                    .write_u8(port.compression().map_or(0, |c| c.id()))
+                    .await?;
+                write_hostname(&mut bytes, host).await?;
+                bytes.write_u16(*target_port).await?;
+            }
+            ForwardTarget::Udp => {}
+            ForwardTarget::Service { name, token } => {
+                bytes
+                    .write_u8(port.compression().map_or(0, |c| c.id()))
+                    .await?;
+                write_hostname(&mut bytes, token).await?;
+                write_hostname(&mut bytes, name).await?;
+            }
+            ForwardTarget::ServiceTunnel {
+                name,
+                token,
+                request_id,
+            } => {
+                bytes
+                    .write_u8(port.compression().map_or(0, |c| c.id()))
+                    .await?;
+                write_hostname(&mut bytes, token).await?;
+                write_hostname(&mut bytes, name).await?;
+                bytes.write_u64(*request_id).await?;
+            }
+            ForwardTarget::Punch {
+                name,
+                token,
+                id,
+                fingerprint,
+            } => {
+                write_hostname(&mut bytes, token).await?;
+                write_hostname(&mut bytes, name).await?;
+                bytes.write_u64(*id).await?;
+                write_hostname(&mut bytes, fingerprint).await?;
+            }
+            ForwardTarget::PunchListen { name, token } => {
+                write_hostname(&mut bytes, token).await?;
+                write_hostname(&mut bytes, name).await?;
+            }
+        }
+
+        let mut stream = Box::pin(Self::open_stream(
+            connector,
+            &port.tunnel_type,
+            0,
+            code,
+            &bytes,
+        ))
+        .await?;
+
+        let status = stream.read_u8().await?;
+        if status != socks::SUCCEEDED {
+            return Err(ForwardRefused(status).into());
+        }
+
+        match target {
+            ForwardTarget::Udp => Ok(stream.framed(&PortType::Udp)),
+            ForwardTarget::Punch { .. } | ForwardTarget::PunchListen { .. } => Ok(stream),
+            _ => Ok(stream.compressed(port.compression())),
+        }
+    }
+
+    /// Opens byte stream tunnel (Tcp, Ws or QUIC stream), authenticates it
+    /// and sends rest of the preamble
+    async fn open_stream(
+        connector: &ConnectorAddr,
+        tunnel_type: &PortType,
+        port: u16,
+        code: u64,
+        preamble: &[u8],
+    ) -> Result<Self> {
+        let stream = match tunnel_type {
+            PortType::Quic => {
+                let quic_port = connector
+                    .quic_port
+                    .ok_or_else(|| color_eyre::eyre::eyre!("QUIC port not set"))?;
+
+                let peer = quic::connect(
+                    &connector.ip,
+                    quic_port,
+                    connector.quic_fingerprint.as_deref(),
+                )
+                .await?;
+                let mut stream = peer.open_bi().await?;
+                stream.write_u8(quic::STREAM_TUNNEL).await?;
+
+                MultiStream::Quic(Box::new(stream))
+            }
+            _ => connector.connect(tunnel_type).await?,
+        };
+
+        let mut stream = connector.authenticate(stream, port, code).await?;
+        stream.write_all(preamble).await?;
+        stream.flush().await?;
+
+        Ok(stream)
+    }
+
+    async fn connect_quic_datagram(
+        connector: &ConnectorAddr,
+        port: &ConnectorPort,
+        code: u64,
+        preamble: &[u8],
+    ) -> Result<Self> {
+        let quic_port = connector
+            .quic_port
+            .ok_or_else(|| color_eyre::eyre::eyre!("QUIC port not set"))?;
+
+        let peer = quic::connect(
+            &connector.ip,
+            quic_port,
+            connector.quic_fingerprint.as_deref(),
+        )
+        .await?;
+        let mut stream = peer.open_bi().await?;
+
+        stream.write_u8(quic::DATAGRAM_TUNNEL).await?;
+        stream.write_u16(port.port_remote).await?;
+        stream.write_u64(code).await?;
+        stream.write_all(preamble).await?;
+
+        let id = stream.read_u32().await?;
+        Ok(MultiStream::QuicDatagram(Box::new(
+            peer.register_flow(id, stream),
+        )))
+    }
+
+    pub fn compressed(self, compression: Option<Compression>) -> Self {
+        match compression {
+            Some(compression) => {
+                MultiStream::Compressed(Box::new(Compressed::new(self, compression)))
             }
+            None => self,
         }
     }
 
-    pub async fn copy_bidirectional<T>(self, s2: T) -> Result<()>
+    /// Adds framing matching the port and tunnel types. Over raw UDP tunnels
+    /// UDP ports keep datagram boundaries and everything else gets reliable
+    /// ordered delivery, over stream tunnels UDP datagrams are length-prefixed.
+    pub fn framed(self, port_type: &PortType) -> Self {
+        match self {
+            MultiStream::UdpLocal(_) | MultiStream::UdpRemote(_) => {
+                if *port_type == PortType::Udp {
+                    MultiStream::Datagrams(Box::new(DatagramFramed::new(self)))
+                } else {
+                    MultiStream::Reliable(framing::reliable(self))
+                }
+            }
+            MultiStream::Tcp(_)
+            | MultiStream::Ws(_)
+            | MultiStream::Quic(_)
+            | MultiStream::Compressed(_)
+            | MultiStream::Noise(_)
+                if *port_type == PortType::Udp =>
+            {
+                MultiStream::LengthPrefixed(Box::new(LengthFramed::new(self)))
+            }
+            _ => self,
+        }
+    }
+
+    /// Same as `copy_bidirectional`, but on Linux plain TCP tunnels are
+    /// forwarded with splice(2) without copying through userspace
+    pub async fn copy_bidirectional_tcp(self, s2: TcpStream, options: &CopyOptions) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        if let MultiStream::Tcp(s1) = &self {
+            splice::copy_bidirectional(s1, &s2, options).await?;
+            return Ok(());
+        }
+
+        self.copy_bidirectional(s2, options).await
+    }
+
+    /// Copies data both ways until one side closes, or nothing is sent for
+    /// `idle_timeout`. Traffic is held back by bandwidth `limits`.
+    pub async fn copy_bidirectional<T>(self, s2: T, options: &CopyOptions) -> Result<()>
     where
         T: AsyncRead + AsyncWrite + Unpin,
     {
+        let datagrams = self.is_datagram();
         match self {
-            MultiStream::Tcp(s) => Self::inner_copy_bidirectional(s, s2).await?,
-            MultiStream::UdpLocal(s) => Self::inner_copy_bidirectional(s, s2).await?,
-            MultiStream::UdpRemote(s) => Self::inner_copy_bidirectional(s, s2).await?,
+            MultiStream::Tcp(s) => {
+                Self::inner_copy_bidirectional(s, s2, options, datagrams).await?
+            }
+            MultiStream::UdpLocal(s) => {
+                Self::inner_copy_bidirectional(s, s2, options, datagrams).await?
+            }
+            MultiStream::UdpRemote(s) => {
+                Self::inner_copy_bidirectional(s, s2, options, datagrams).await?
+            }
+            MultiStream::Ws(s) => Self::inner_copy_bidirectional(s, s2, options, datagrams).await?,
+            MultiStream::Quic(s) => {
+                Self::inner_copy_bidirectional(s, s2, options, datagrams).await?
+            }
+            MultiStream::QuicDatagram(s) => {
+                Self::inner_copy_bidirectional(s, s2, options, datagrams).await?
+            }
+            MultiStream::Datagrams(s) => {
+                Self::inner_copy_bidirectional(s, s2, options, datagrams).await?
+            }
+            MultiStream::Reliable(s) => {
+                Self::inner_copy_bidirectional(s, s2, options, datagrams).await?
+            }
+            MultiStream::LengthPrefixed(s) => {
+                Self::inner_copy_bidirectional(s, s2, options, datagrams).await?
+            }
+            MultiStream::Compressed(s) => {
+                Self::inner_copy_bidirectional(s, s2, options, datagrams).await?
+            }
+            MultiStream::Noise(s) => {
+                Self::inner_copy_bidirectional(s, s2, options, datagrams).await?
+            }
         }
 
         Ok(())
     }
 
-    async fn inner_copy_bidirectional<T1, T2>(mut stream1: T1, mut stream2: T2) -> Result<()>
+    async fn inner_copy_bidirectional<T1, T2>(
+        mut stream1: T1,
+        mut stream2: T2,
+        options: &CopyOptions,
+        datagrams: bool,
+    ) -> Result<()>
     where
         T1: AsyncRead + AsyncWrite + Unpin,
         T2: AsyncRead + AsyncWrite + Unpin,
     {
-        let local_buf = &mut [0u8; BUFFER_SIZE];
-        let remote_buf = &mut [0u8; BUFFER_SIZE];
+        if datagrams {
+            return Self::inner_copy_datagrams(stream1, stream2, options).await;
+        }
 
-        loop {
+        // start with small buffers so idle connections don't hold big ones (on
+        // the heap, arrays inside the future overflow the stack in debug builds)
+        let local_buf = &mut vec![0u8; SMALL_BUFFER_SIZE];
+        let remote_buf = &mut vec![0u8; SMALL_BUFFER_SIZE];
+        let idle_timeout = options.idle_timeout;
+
+        let mut stream1_eof = false;
+        let mut stream2_eof = false;
+
+        // EOF on one side only shuts down writing to the other one (half-close),
+        // the opposite direction keeps going until it's closed as well
+        while !(stream1_eof && stream2_eof) {
             tokio::select! {
-                res = stream1.read(&mut local_buf[..])=> {
+                res = stream1.read(&mut local_buf[..]), if !stream1_eof => {
                     if res.is_err() {
                         stream1.shutdown().await?;
                         stream2.shutdown().await?;
@@ -114,12 +722,26 @@ impl MultiStream {
 
                     let n = res?;
                     if n == 0 {
-                        break;
+                        stream1_eof = true;
+                        stream2.shutdown().await?;
+                        continue;
                     }
 
-                    stream2.write_all(&local_buf[..n]).await?;
+                    if n < local_buf.len() {
+                        options.limits.consume(n).await;
+                        stream2.write_all(&local_buf[..n]).await?;
+                    } else {
+                        Self::write_drained(
+                            &mut stream1,
+                            &mut stream2,
+                            &local_buf[..n],
+                            &options.limits,
+                        )
+                        .await?;
+                    }
+                    stream2.flush().await?;
                 }
-                res = stream2.read(&mut remote_buf[..]) => {
+                res = stream2.read(&mut remote_buf[..]), if !stream2_eof => {
                     if res.is_err() {
                         stream1.shutdown().await?;
                         stream2.shutdown().await?;
@@ -128,14 +750,211 @@ impl MultiStream {
 
                     let n = res?;
                     if n == 0 {
-                        break;
+                        stream2_eof = true;
+                        stream1.shutdown().await?;
+                        continue;
+                    }
+
+                    if n < remote_buf.len() {
+                        options.limits.consume(n).await;
+                        stream1.write_all(&remote_buf[..n]).await?;
+                    } else {
+                        Self::write_drained(
+                            &mut stream2,
+                            &mut stream1,
+                            &remote_buf[..n],
+                            &options.limits,
+                        )
+                        .await?;
+                    }
+                    stream1.flush().await?;
+                }
+                _ = tokio::time::sleep(idle_timeout.unwrap_or_default()), if idle_timeout.is_some() => {
+                    println!("Closing connection idle for {:?}", idle_timeout.unwrap_or_default());
+                    if let Some(on_idle) = options.on_idle {
+                        on_idle();
                     }
+                    stream1.shutdown().await?;
+                    stream2.shutdown().await?;
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
 
-                    stream1.write_all(&remote_buf[..n]).await?;
+    /// Datagram flows can't be half-closed, EOF of either side (udpflow's read
+    /// timeout included) ends the whole flow just like idle timeout. Every
+    /// datagram is read into pooled buffer, idle flows don't hold any.
+    async fn inner_copy_datagrams<T1, T2>(
+        mut stream1: T1,
+        mut stream2: T2,
+        options: &CopyOptions,
+    ) -> Result<()>
+    where
+        T1: AsyncRead + AsyncWrite + Unpin,
+        T2: AsyncRead + AsyncWrite + Unpin,
+    {
+        let idle_timeout = options.idle_timeout;
+
+        loop {
+            tokio::select! {
+                res = buffer_pool::read_datagram(&mut stream1) => {
+                    let (buf, n) = match res {
+                        Ok((buf, n)) if n > 0 => (buf, n),
+                        _ => break,
+                    };
+
+                    options.limits.consume(n).await;
+                    stream2.write_all(&buf[..n]).await?;
+                    stream2.flush().await?;
+                }
+                res = buffer_pool::read_datagram(&mut stream2) => {
+                    let (buf, n) = match res {
+                        Ok((buf, n)) if n > 0 => (buf, n),
+                        _ => break,
+                    };
+
+                    options.limits.consume(n).await;
+                    stream1.write_all(&buf[..n]).await?;
+                    stream1.flush().await?;
+                }
+                _ = tokio::time::sleep(idle_timeout.unwrap_or_default()), if idle_timeout.is_some() => {
+                    println!("Closing connection idle for {:?}", idle_timeout.unwrap_or_default());
+                    if let Some(on_idle) = options.on_idle {
+                        on_idle();
+                    }
+                    break;
                 }
             }
         }
 
+        stream1.shutdown().await?;
+        stream2.shutdown().await?;
         Ok(())
     }
+
+    /// Small read buffer got filled, so more data is probably waiting. Reads
+    /// whatever is already there (without waiting) into pooled buffer and
+    /// writes it at once.
+    async fn write_drained<R, W>(
+        reader: &mut R,
+        writer: &mut W,
+        first: &[u8],
+        limits: &Limits,
+    ) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = buffer_pool::get();
+        if buf.len() <= first.len() {
+            limits.consume(first.len()).await;
+            writer.write_all(first).await?;
+            return Ok(());
+        }
+
+        buf[..first.len()].copy_from_slice(first);
+        let mut filled = first.len();
+        while filled < buf.len() {
+            // EOF and errors show up again on the next regular read
+            match reader.read(&mut buf[filled..]).now_or_never() {
+                Some(Ok(n)) if n > 0 => filled += n,
+                _ => break,
+            }
+        }
+
+        limits.consume(filled).await;
+        writer.write_all(&buf[..filled]).await?;
+        Ok(())
+    }
+
+    /// Streams which keep datagram boundaries, every read returns one datagram
+    fn is_datagram(&self) -> bool {
+        matches!(
+            self,
+            MultiStream::UdpLocal(_)
+                | MultiStream::UdpRemote(_)
+                | MultiStream::QuicDatagram(_)
+                | MultiStream::Datagrams(_)
+                | MultiStream::LengthPrefixed(_)
+        )
+    }
+}
+
+impl AsyncRead for MultiStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MultiStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            MultiStream::UdpLocal(s) => Pin::new(s).poll_read(cx, buf),
+            MultiStream::UdpRemote(s) => Pin::new(s).poll_read(cx, buf),
+            MultiStream::Ws(s) => Pin::new(s).poll_read(cx, buf),
+            MultiStream::Quic(s) => Pin::new(s).poll_read(cx, buf),
+            MultiStream::QuicDatagram(s) => Pin::new(s).poll_read(cx, buf),
+            MultiStream::Datagrams(s) => Pin::new(s).poll_read(cx, buf),
+            MultiStream::Reliable(s) => Pin::new(s).poll_read(cx, buf),
+            MultiStream::LengthPrefixed(s) => Pin::new(s).poll_read(cx, buf),
+            MultiStream::Compressed(s) => Pin::new(s).poll_read(cx, buf),
+            MultiStream::Noise(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MultiStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MultiStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            MultiStream::UdpLocal(s) => Pin::new(s).poll_write(cx, buf),
+            MultiStream::UdpRemote(s) => Pin::new(s).poll_write(cx, buf),
+            MultiStream::Ws(s) => Pin::new(s).poll_write(cx, buf),
+            MultiStream::Quic(s) => Pin::new(s).poll_write(cx, buf),
+            MultiStream::QuicDatagram(s) => Pin::new(s).poll_write(cx, buf),
+            MultiStream::Datagrams(s) => Pin::new(s).poll_write(cx, buf),
+            MultiStream::Reliable(s) => Pin::new(s).poll_write(cx, buf),
+            MultiStream::LengthPrefixed(s) => Pin::new(s).poll_write(cx, buf),
+            MultiStream::Compressed(s) => Pin::new(s).poll_write(cx, buf),
+            MultiStream::Noise(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MultiStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            MultiStream::UdpLocal(s) => Pin::new(s).poll_flush(cx),
+            MultiStream::UdpRemote(s) => Pin::new(s).poll_flush(cx),
+            MultiStream::Ws(s) => Pin::new(s).poll_flush(cx),
+            MultiStream::Quic(s) => Pin::new(s).poll_flush(cx),
+            MultiStream::QuicDatagram(s) => Pin::new(s).poll_flush(cx),
+            MultiStream::Datagrams(s) => Pin::new(s).poll_flush(cx),
+            MultiStream::Reliable(s) => Pin::new(s).poll_flush(cx),
+            MultiStream::LengthPrefixed(s) => Pin::new(s).poll_flush(cx),
+            MultiStream::Compressed(s) => Pin::new(s).poll_flush(cx),
+            MultiStream::Noise(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MultiStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            MultiStream::UdpLocal(s) => Pin::new(s).poll_shutdown(cx),
+            MultiStream::UdpRemote(s) => Pin::new(s).poll_shutdown(cx),
+            MultiStream::Ws(s) => Pin::new(s).poll_shutdown(cx),
+            MultiStream::Quic(s) => Pin::new(s).poll_shutdown(cx),
+            MultiStream::QuicDatagram(s) => Pin::new(s).poll_shutdown(cx),
+            MultiStream::Datagrams(s) => Pin::new(s).poll_shutdown(cx),
+            MultiStream::Reliable(s) => Pin::new(s).poll_shutdown(cx),
+            MultiStream::LengthPrefixed(s) => Pin::new(s).poll_shutdown(cx),
+            MultiStream::Compressed(s) => Pin::new(s).poll_shutdown(cx),
+            MultiStream::Noise(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
 }
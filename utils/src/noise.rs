@@ -0,0 +1,274 @@
+use color_eyre::Result;
+use sha2::{Digest, Sha256};
+use snow::{Builder, HandshakeState, TransportState};
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Both sides only share connector code, so it's mixed in as pre-shared key
+/// and ephemeral keys give forward secrecy
+const PATTERN: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+const PROLOGUE: &[u8] = b"local-forwarder";
+
+const MAX_MESSAGE: usize = 65535;
+const TAG_SIZE: usize = 16;
+const MAX_PAYLOAD: usize = MAX_MESSAGE - TAG_SIZE;
+const LENGTH_SIZE: usize = 2;
+
+fn builder(psk: &[u8; 32]) -> Result<Builder<'_>> {
+    Ok(Builder::new(PATTERN.parse()?)
+        .prologue(PROLOGUE)
+        .psk(0, psk))
+}
+
+fn psk(code: u64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(PROLOGUE);
+    hasher.update(code.to_be_bytes());
+    hasher.finalize().into()
+}
+
+async fn write_message<S>(stream: &mut S, handshake: &mut HandshakeState) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; MAX_MESSAGE];
+    let n = handshake.write_message(&[], &mut buf)?;
+
+    stream.write_u16(n as u16).await?;
+    stream.write_all(&buf[..n]).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_message<S>(stream: &mut S) -> Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = stream.read_u16().await?;
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+
+    Ok(buf)
+}
+
+/// Runs handshake as the client, fails if server has different code
+pub async fn initiate<S>(mut stream: S, code: u64) -> Result<NoiseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let psk = psk(code);
+    let mut handshake = builder(&psk)?.build_initiator()?;
+
+    write_message(&mut stream, &mut handshake).await?;
+    let message = read_message(&mut stream).await?;
+    handshake.read_message(&message, &mut vec![0; MAX_MESSAGE])?;
+
+    Ok(NoiseStream::new(stream, handshake.into_transport_mode()?))
+}
+
+/// Runs handshake as the server, returns `None` if client used different code
+pub async fn respond<S>(mut stream: S, code: u64) -> Result<Option<NoiseStream<S>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let psk = psk(code);
+    let mut handshake = builder(&psk)?.build_responder()?;
+
+    let message = read_message(&mut stream).await?;
+    if handshake
+        .read_message(&message, &mut vec![0; MAX_MESSAGE])
+        .is_err()
+    {
+        return Ok(None);
+    }
+    write_message(&mut stream, &mut handshake).await?;
+
+    Ok(Some(NoiseStream::new(
+        stream,
+        handshake.into_transport_mode()?,
+    )))
+}
+
+/// Byte stream sent as length-prefixed Noise messages. Shutdown sends empty
+/// message, so truncated stream is an error and not a clean EOF.
+pub struct NoiseStream<S> {
+    inner: S,
+    transport: TransportState,
+
+    /// Message being read, length prefix included
+    read_buf: Vec<u8>,
+    read_filled: usize,
+    plain: Vec<u8>,
+    plain_pos: usize,
+    read_eof: bool,
+
+    /// Encrypted message not written to inner stream yet
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    write_eof: bool,
+}
+
+impl<S> NoiseStream<S> {
+    fn new(inner: S, transport: TransportState) -> Self {
+        Self {
+            inner,
+            transport,
+            read_buf: vec![0; LENGTH_SIZE],
+            read_filled: 0,
+            plain: Vec::new(),
+            plain_pos: 0,
+            read_eof: false,
+            write_buf: Vec::new(),
+            write_pos: 0,
+            write_eof: false,
+        }
+    }
+}
+
+fn to_io_error(e: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+impl<S> NoiseStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.write_buf[self.write_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+
+        self.write_buf.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn encrypt(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.write_buf
+            .resize(LENGTH_SIZE + payload.len() + TAG_SIZE, 0);
+        let n = self
+            .transport
+            .write_message(payload, &mut self.write_buf[LENGTH_SIZE..])
+            .map_err(to_io_error)?;
+
+        self.write_buf[..LENGTH_SIZE].copy_from_slice(&(n as u16).to_be_bytes());
+        self.write_buf.truncate(LENGTH_SIZE + n);
+        Ok(())
+    }
+}
+
+impl<S> AsyncRead for NoiseStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let n = buf.remaining().min(this.plain.len() - this.plain_pos);
+                buf.put_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.read_eof {
+                return Poll::Ready(Ok(()));
+            }
+
+            while this.read_filled < this.read_buf.len() {
+                let mut read_buf = ReadBuf::new(&mut this.read_buf[this.read_filled..]);
+                ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+
+                let n = read_buf.filled().len();
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                this.read_filled += n;
+
+                // length prefix is complete, read rest of the message
+                if this.read_filled == LENGTH_SIZE && this.read_buf.len() == LENGTH_SIZE {
+                    let len = u16::from_be_bytes([this.read_buf[0], this.read_buf[1]]);
+                    this.read_buf.resize(LENGTH_SIZE + len as usize, 0);
+                }
+            }
+
+            let message = &this.read_buf[LENGTH_SIZE..];
+            this.plain.resize(message.len().saturating_sub(TAG_SIZE), 0);
+            let n = this
+                .transport
+                .read_message(message, &mut this.plain)
+                .map_err(to_io_error)?;
+            this.plain.truncate(n);
+            this.plain_pos = 0;
+
+            this.read_buf.truncate(LENGTH_SIZE);
+            this.read_filled = 0;
+
+            // empty message marks end of the stream
+            if n == 0 {
+                this.read_eof = true;
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for NoiseStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // empty message would be taken as EOF
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        ready!(this.poll_write_pending(cx))?;
+
+        let n = buf.len().min(MAX_PAYLOAD);
+        this.encrypt(&buf[..n])?;
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_write_pending(cx))?;
+        if !this.write_eof {
+            this.encrypt(&[])?;
+            this.write_eof = true;
+            ready!(this.poll_write_pending(cx))?;
+        }
+
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,377 @@
+use crate::socks::{self, Address};
+use base64::Engine;
+use color_eyre::Result;
+use std::net::{IpAddr, SocketAddr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const MAX_RESPONSE_SIZE: usize = 8192;
+
+/// Outbound proxy used for connections to the connector
+#[derive(Debug, Clone)]
+pub enum Proxy {
+    /// HTTP proxy with CONNECT method (`http://[user:pass@]ip:port`)
+    Http {
+        addr: String,
+        auth: Option<(String, String)>,
+    },
+    /// SOCKS5 proxy (`socks5://[user:pass@]ip:port`)
+    Socks5 {
+        addr: String,
+        auth: Option<(String, String)>,
+    },
+}
+
+impl Proxy {
+    pub fn parse(url: &str) -> Result<Self> {
+        let (scheme, rest) = url.split_once("://").unwrap_or(("http", url));
+        let rest = rest.trim_end_matches('/');
+
+        let (auth, addr) = match rest.rsplit_once('@') {
+            Some((auth, addr)) => {
+                let (user, pass) = auth.split_once(':').unwrap_or((auth, ""));
+                (Some((user.to_string(), pass.to_string())), addr.to_string())
+            }
+            None => (None, rest.to_string()),
+        };
+
+        match scheme.to_lowercase().as_str() {
+            "http" => Ok(Proxy::Http { addr, auth }),
+            "socks5" => Ok(Proxy::Socks5 { addr, auth }),
+            _ => Err(color_eyre::eyre::eyre!("Invalid proxy type: {}", scheme)),
+        }
+    }
+
+    /// Opens connection to `host:port` through the proxy
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        match self {
+            Proxy::Http { addr, auth } => http_connect(addr, auth, host, port).await,
+            Proxy::Socks5 { addr, auth } => socks5_connect(addr, auth, host, port).await,
+        }
+    }
+}
+
+async fn http_connect(
+    addr: &str,
+    auth: &Option<(String, String)>,
+    host: &str,
+    port: u16,
+) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr).await?;
+
+    // IPv6 addresses need brackets in authority
+    let authority = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+        _ => format!("{}:{}", host, port),
+    };
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some((user, pass)) = auth {
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, pass));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await?;
+
+    // read byte by byte, so nothing after response head is consumed
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await?);
+        if head.len() > MAX_RESPONSE_SIZE {
+            color_eyre::eyre::bail!("Proxy response too large");
+        }
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let status = head.split_whitespace().nth(1).unwrap_or_default();
+    if status != "200" {
+        color_eyre::eyre::bail!(
+            "Proxy refused connection: {}",
+            head.lines().next().unwrap_or_default()
+        );
+    }
+
+    Ok(stream)
+}
+
+async fn socks5_connect(
+    addr: &str,
+    auth: &Option<(String, String)>,
+    host: &str,
+    port: u16,
+) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let method = match auth {
+        Some(_) => socks::USER_PASS,
+        None => socks::NO_AUTH,
+    };
+
+    stream.write_all(&[socks::VERSION, 1, method]).await?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [socks::VERSION, method] {
+        color_eyre::eyre::bail!("Proxy doesn't support required authentication method");
+    }
+
+    if let Some((user, pass)) = auth {
+        if user.len() > u8::MAX as usize || pass.len() > u8::MAX as usize {
+            color_eyre::eyre::bail!("Proxy credentials too long");
+        }
+
+        let mut bytes = vec![0x01, user.len() as u8];
+        bytes.extend_from_slice(user.as_bytes());
+        bytes.push(pass.len() as u8);
+        bytes.extend_from_slice(pass.as_bytes());
+        stream.write_all(&bytes).await?;
+
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            color_eyre::eyre::bail!("Proxy authentication failed");
+        }
+    }
+
+    let address = match host.parse::<IpAddr>() {
+        Ok(ip) => Address::Ip(SocketAddr::new(ip, port)),
+        Err(_) => Address::Domain(host.to_string(), port),
+    };
+    let mut bytes = vec![socks::VERSION, socks::CONNECT, 0x00];
+    address.encode(&mut bytes)?;
+    stream.write_all(&bytes).await?;
+
+    let mut reply = [0; 3];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != socks::SUCCEEDED {
+        color_eyre::eyre::bail!("Proxy refused connection (reply {})", reply[1]);
+    }
+
+    // skip bound address
+    Address::read(&mut stream).await?;
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{net::TcpListener, task::JoinHandle};
+
+    const GREETING: &[u8] = b"hello through proxy";
+
+    /// Accepts one CONNECT request, answers it and sends `GREETING` through
+    /// the tunnel when it's accepted. Returns the request head.
+    async fn http_proxy(credentials: Option<&str>) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let expected = credentials.map(|c| {
+            format!(
+                "Proxy-Authorization: Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(c)
+            )
+        });
+
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            let head = String::from_utf8(head).unwrap();
+
+            let authorized = expected.as_ref().is_none_or(|e| head.contains(e.as_str()));
+            if authorized {
+                stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+                stream.write_all(GREETING).await.unwrap();
+            } else {
+                let response = b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n";
+                stream.write_all(response).await.unwrap();
+            }
+
+            head
+        });
+
+        (addr, task)
+    }
+
+    /// Accepts one SOCKS5 CONNECT, checks username/password if `credentials`
+    /// are set and sends `GREETING` through the tunnel. Returns the requested
+    /// address.
+    async fn socks5_proxy(
+        credentials: Option<(&str, &str)>,
+    ) -> (String, JoinHandle<Option<Address>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let credentials = credentials.map(|(u, p)| (u.to_string(), p.to_string()));
+
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut header = [0; 2];
+            stream.read_exact(&mut header).await.unwrap();
+            let mut methods = vec![0; header[1] as usize];
+            stream.read_exact(&mut methods).await.unwrap();
+
+            let method = match credentials {
+                Some(_) => socks::USER_PASS,
+                None => socks::NO_AUTH,
+            };
+            if !methods.contains(&method) {
+                let reply = [socks::VERSION, socks::NO_ACCEPTABLE_METHODS];
+                stream.write_all(&reply).await.unwrap();
+                return None;
+            }
+            stream.write_all(&[socks::VERSION, method]).await.unwrap();
+
+            if let Some((user, pass)) = credentials {
+                stream.read_u8().await.unwrap();
+                let mut sent_user = vec![0; stream.read_u8().await.unwrap() as usize];
+                stream.read_exact(&mut sent_user).await.unwrap();
+                let mut sent_pass = vec![0; stream.read_u8().await.unwrap() as usize];
+                stream.read_exact(&mut sent_pass).await.unwrap();
+
+                let ok = sent_user == user.as_bytes() && sent_pass == pass.as_bytes();
+                stream.write_all(&[0x01, !ok as u8]).await.unwrap();
+                if !ok {
+                    return None;
+                }
+            }
+
+            let mut request = [0; 3];
+            stream.read_exact(&mut request).await.unwrap();
+            let address = Address::read(&mut stream).await.unwrap();
+
+            let mut reply = vec![socks::VERSION, socks::SUCCEEDED, 0x00];
+            Address::unspecified().encode(&mut reply).unwrap();
+            stream.write_all(&reply).await.unwrap();
+            stream.write_all(GREETING).await.unwrap();
+
+            Some(address)
+        });
+
+        (addr, task)
+    }
+
+    async fn read_greeting(mut stream: TcpStream) -> Vec<u8> {
+        let mut buf = vec![0; GREETING.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn http_connect_with_credentials() {
+        let (addr, proxy) = http_proxy(Some("user:secret")).await;
+        let proxy_url = format!("http://user:secret@{}", addr);
+
+        let stream = Proxy::parse(&proxy_url)
+            .unwrap()
+            .connect("connector.example", 1337)
+            .await
+            .unwrap();
+
+        assert_eq!(read_greeting(stream).await, GREETING);
+        let head = proxy.await.unwrap();
+        assert!(head.starts_with("CONNECT connector.example:1337 HTTP/1.1\r\n"));
+        assert!(head.contains("Host: connector.example:1337\r\n"));
+    }
+
+    #[tokio::test]
+    async fn http_connect_with_wrong_credentials() {
+        let (addr, proxy) = http_proxy(Some("user:secret")).await;
+        let proxy_url = format!("http://user:wrong@{}", addr);
+
+        let res = Proxy::parse(&proxy_url)
+            .unwrap()
+            .connect("connector.example", 1337)
+            .await;
+
+        let error = res.unwrap_err().to_string();
+        assert!(error.contains("407"), "{}", error);
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn http_connect_brackets_ipv6() {
+        let (addr, proxy) = http_proxy(None).await;
+
+        let stream = Proxy::parse(&addr)
+            .unwrap()
+            .connect("2001:db8::1", 1337)
+            .await
+            .unwrap();
+
+        assert_eq!(read_greeting(stream).await, GREETING);
+        let head = proxy.await.unwrap();
+        assert!(head.starts_with("CONNECT [2001:db8::1]:1337 HTTP/1.1\r\n"));
+        assert!(head.contains("Host: [2001:db8::1]:1337\r\n"));
+    }
+
+    #[tokio::test]
+    async fn socks5_connect_without_credentials() {
+        let (addr, proxy) = socks5_proxy(None).await;
+        let proxy_url = format!("socks5://{}", addr);
+
+        let stream = Proxy::parse(&proxy_url)
+            .unwrap()
+            .connect("connector.example", 1337)
+            .await
+            .unwrap();
+
+        assert_eq!(read_greeting(stream).await, GREETING);
+        let address = proxy.await.unwrap();
+        assert_eq!(
+            address,
+            Some(Address::Domain("connector.example".to_string(), 1337))
+        );
+    }
+
+    #[tokio::test]
+    async fn socks5_connect_with_credentials() {
+        let (addr, proxy) = socks5_proxy(Some(("user", "secret"))).await;
+        let proxy_url = format!("socks5://user:secret@{}", addr);
+
+        let stream = Proxy::parse(&proxy_url)
+            .unwrap()
+            .connect("2001:db8::1", 1337)
+            .await
+            .unwrap();
+
+        assert_eq!(read_greeting(stream).await, GREETING);
+        let address = proxy.await.unwrap();
+        assert_eq!(
+            address,
+            Some(Address::Ip("[2001:db8::1]:1337".parse().unwrap()))
+        );
+    }
+
+    #[tokio::test]
+    async fn socks5_connect_with_wrong_credentials() {
+        let (addr, proxy) = socks5_proxy(Some(("user", "secret"))).await;
+        let proxy_url = format!("socks5://user:wrong@{}", addr);
+
+        let res = Proxy::parse(&proxy_url)
+            .unwrap()
+            .connect("connector.example", 1337)
+            .await;
+
+        let error = res.unwrap_err().to_string();
+        assert!(error.contains("authentication failed"), "{}", error);
+        assert_eq!(proxy.await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn socks5_connect_without_required_credentials() {
+        let (addr, proxy) = socks5_proxy(Some(("user", "secret"))).await;
+        let proxy_url = format!("socks5://{}", addr);
+
+        let res = Proxy::parse(&proxy_url)
+            .unwrap()
+            .connect("connector.example", 1337)
+            .await;
+
+        let error = res.unwrap_err().to_string();
+        assert!(error.contains("authentication method"), "{}", error);
+        assert_eq!(proxy.await.unwrap(), None);
+    }
+}
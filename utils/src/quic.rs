@@ -0,0 +1,461 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use color_eyre::Result;
+use quinn::{Connection, Endpoint, RecvStream, SendStream};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::mpsc,
+};
+
+pub const STREAM_TUNNEL: u8 = 0;
+pub const DATAGRAM_TUNNEL: u8 = 1;
+
+const ALPN: &[u8] = b"local-forwarder";
+const SERVER_NAME: &str = "lf-server";
+const KEEP_ALIVE_INTERVAL: u64 = 10;
+const FLOW_QUEUE_SIZE: usize = 256;
+
+type Flows = Arc<Mutex<HashMap<u32, mpsc::Sender<Bytes>>>>;
+
+static CLIENT: tokio::sync::Mutex<Option<QuicPeer>> = tokio::sync::Mutex::const_new(None);
+
+/// QUIC connection shared by all tunnels of one client. Forwarded TCP
+/// connections are QUIC streams, UDP flows are QUIC datagrams.
+#[derive(Clone)]
+pub struct QuicPeer {
+    connection: Connection,
+    flows: Flows,
+    next_flow: Arc<AtomicU32>,
+}
+
+impl QuicPeer {
+    pub fn new(connection: Connection) -> Self {
+        let peer = Self {
+            connection,
+            flows: Arc::new(Mutex::new(HashMap::new())),
+            next_flow: Arc::new(AtomicU32::new(0)),
+        };
+
+        let connection = peer.connection.clone();
+        let flows = peer.flows.clone();
+        tokio::spawn(async move {
+            while let Ok(datagram) = connection.read_datagram().await {
+                if datagram.len() < 4 {
+                    continue;
+                }
+
+                let id = u32::from_be_bytes([datagram[0], datagram[1], datagram[2], datagram[3]]);
+                let sender = flows.lock().ok().and_then(|f| f.get(&id).cloned());
+                if let Some(sender) = sender {
+                    // queue full means local side is too slow, drop like UDP would
+                    let _ = sender.try_send(datagram.slice(4..));
+                }
+            }
+        });
+
+        peer
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.connection.close_reason().is_some()
+    }
+
+    pub async fn open_bi(&self) -> Result<QuicStream> {
+        let (send, recv) = self.connection.open_bi().await?;
+        Ok(QuicStream { send, recv })
+    }
+
+    pub async fn accept_bi(&self) -> Result<QuicStream> {
+        let (send, recv) = self.connection.accept_bi().await?;
+        Ok(QuicStream { send, recv })
+    }
+
+    pub fn next_flow_id(&self) -> u32 {
+        self.next_flow.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Registers datagram flow, `stream` is kept open as long as the flow lives
+    pub fn register_flow(&self, id: u32, stream: QuicStream) -> DatagramFlow {
+        let (sender, receiver) = mpsc::channel(FLOW_QUEUE_SIZE);
+        if let Ok(mut flows) = self.flows.lock() {
+            flows.insert(id, sender);
+        }
+
+        DatagramFlow {
+            id,
+            connection: self.connection.clone(),
+            flows: self.flows.clone(),
+            receiver,
+            stream,
+        }
+    }
+}
+
+/// Self-signed certificate (DER) and its private key (PKCS#8 DER) QUIC
+/// endpoint identifies with, the other side can pin its fingerprint
+#[derive(Clone)]
+pub struct Identity {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+impl Identity {
+    pub fn generate() -> Result<Self> {
+        let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+
+        Ok(Self {
+            cert: cert.serialize_der()?,
+            key: cert.serialize_private_key_der(),
+        })
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.cert)
+    }
+}
+
+/// SHA-256 of the certificate (DER) as lowercase hex
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Normalizes fingerprint written as hex (optionally with colons), `None`
+/// if it isn't SHA-256 fingerprint
+pub fn parse_fingerprint(fingerprint: &str) -> Option<String> {
+    let fingerprint = fingerprint.replace(':', "").to_lowercase();
+    let valid = fingerprint.len() == 64 && fingerprint.chars().all(|c| c.is_ascii_hexdigit());
+
+    valid.then_some(fingerprint)
+}
+
+/// Returns QUIC connection to the connector, reusing existing one if it is
+/// still alive. Server certificate has to match `fingerprint`, without it
+/// any certificate is accepted.
+pub async fn connect(host: &str, port: u16, fingerprint: Option<&str>) -> Result<QuicPeer> {
+    let mut client = CLIENT.lock().await;
+    if let Some(peer) = client.as_ref().filter(|p| !p.is_closed()) {
+        return Ok(peer.clone());
+    }
+
+    let addr = tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| color_eyre::eyre::eyre!("Could not resolve {}", host))?;
+
+    if fingerprint.is_none() {
+        eprintln!("QUIC server certificate isn't verified, set its fingerprint to pin it");
+    }
+
+    let endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
+    let connection = endpoint
+        .connect_with(client_config(fingerprint), addr, SERVER_NAME)?
+        .await?;
+
+    let peer = QuicPeer::new(connection);
+    client.replace(peer.clone());
+
+    Ok(peer)
+}
+
+/// Creates server endpoint with its self-signed certificate. Clients pin
+/// its fingerprint (if they have it), they are authenticated by the code
+/// like on TCP.
+pub fn server_endpoint(port: u16, identity: &Identity) -> Result<Endpoint> {
+    Ok(Endpoint::server(
+        server_config(identity)?,
+        ([0, 0, 0, 0], port).into(),
+    )?)
+}
+
+/// Creates endpoint of direct path between two clients on already punched
+/// socket, one side accepts and the other connects with `connect_peer`.
+/// Both present `identity` and pin certificate of the other side by the
+/// fingerprint they got from the rendezvous, accepting side passes it here.
+pub fn peer_endpoint(
+    socket: std::net::UdpSocket,
+    identity: &Identity,
+    accept_from: Option<&str>,
+) -> Result<Endpoint> {
+    let server_config = match accept_from {
+        Some(fingerprint) => Some(peer_server_config(identity, fingerprint)?),
+        None => None,
+    };
+
+    Ok(Endpoint::new(
+        quinn::EndpointConfig::default(),
+        server_config,
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    )?)
+}
+
+pub async fn connect_peer(
+    endpoint: &Endpoint,
+    addr: SocketAddr,
+    identity: &Identity,
+    fingerprint: &str,
+) -> Result<QuicPeer> {
+    let connection = endpoint
+        .connect_with(
+            peer_client_config(identity, fingerprint)?,
+            addr,
+            SERVER_NAME,
+        )?
+        .await?;
+
+    Ok(QuicPeer::new(connection))
+}
+
+fn client_config(fingerprint: Option<&str>) -> quinn::ClientConfig {
+    let verifier = PinnedServerVerification(fingerprint.map(|f| f.to_string()));
+    let crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    quic_client_config(crypto)
+}
+
+/// Client config of direct path, it pins the peer and shows `identity` to it
+fn peer_client_config(identity: &Identity, fingerprint: &str) -> Result<quinn::ClientConfig> {
+    let verifier = PinnedServerVerification(Some(fingerprint.to_string()));
+    let crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_client_auth_cert(
+            vec![rustls::Certificate(identity.cert.clone())],
+            rustls::PrivateKey(identity.key.clone()),
+        )?;
+
+    Ok(quic_client_config(crypto))
+}
+
+fn quic_client_config(mut crypto: rustls::ClientConfig) -> quinn::ClientConfig {
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+    config.transport_config(transport_config());
+    config
+}
+
+fn server_config(identity: &Identity) -> Result<quinn::ServerConfig> {
+    let crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth();
+
+    quic_server_config(crypto, identity)
+}
+
+/// Server config of direct path, only the peer with pinned certificate can
+/// connect
+fn peer_server_config(identity: &Identity, fingerprint: &str) -> Result<quinn::ServerConfig> {
+    let verifier = PinnedClientVerification(fingerprint.to_string());
+    let crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(Arc::new(verifier));
+
+    quic_server_config(crypto, identity)
+}
+
+fn quic_server_config(
+    crypto: rustls::ConfigBuilder<rustls::ServerConfig, rustls::server::WantsServerCert>,
+    identity: &Identity,
+) -> Result<quinn::ServerConfig> {
+    let key = rustls::PrivateKey(identity.key.clone());
+    let cert = rustls::Certificate(identity.cert.clone());
+
+    let mut crypto = crypto.with_single_cert(vec![cert], key)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+fn transport_config() -> Arc<quinn::TransportConfig> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(Duration::from_secs(KEEP_ALIVE_INTERVAL)));
+
+    Arc::new(transport)
+}
+
+/// Self-signed certificates can't be verified by a CA, so the server is
+/// recognized by fingerprint of its certificate. Without fingerprint every
+/// certificate is accepted.
+struct PinnedServerVerification(Option<String>);
+
+impl rustls::client::ServerCertVerifier for PinnedServerVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        match &self.0 {
+            Some(pinned) if *pinned != fingerprint(&end_entity.0) => Err(rustls::Error::General(
+                "certificate doesn't match pinned fingerprint".to_string(),
+            )),
+            _ => Ok(rustls::client::ServerCertVerified::assertion()),
+        }
+    }
+}
+
+/// Client certificate of direct path peer is checked the same way, but it
+/// is required and has to match
+struct PinnedClientVerification(String);
+
+impl rustls::server::ClientCertVerifier for PinnedClientVerification {
+    fn client_auth_root_subjects(&self) -> &[rustls::DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _now: SystemTime,
+    ) -> Result<rustls::server::ClientCertVerified, rustls::Error> {
+        match self.0 == fingerprint(&end_entity.0) {
+            true => Ok(rustls::server::ClientCertVerified::assertion()),
+            false => Err(rustls::Error::General(
+                "certificate doesn't match pinned fingerprint".to_string(),
+            )),
+        }
+    }
+}
+
+/// Bidirectional QUIC stream carrying one forwarded connection
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_shutdown(cx)
+    }
+}
+
+/// UDP flow carried in QUIC datagrams prefixed with flow id. Every write is
+/// sent as one datagram and every read returns one, so packet boundaries
+/// are preserved.
+pub struct DatagramFlow {
+    id: u32,
+    connection: Connection,
+    flows: Flows,
+    receiver: mpsc::Receiver<Bytes>,
+    stream: QuicStream,
+}
+
+impl DatagramFlow {
+    /// Sends flow id to the other side (server side, after registering)
+    pub async fn announce(&mut self) -> Result<()> {
+        self.stream.write_u32(self.id).await?;
+        self.stream.flush().await?;
+
+        Ok(())
+    }
+}
+
+impl Drop for DatagramFlow {
+    fn drop(&mut self) {
+        if let Ok(mut flows) = self.flows.lock() {
+            flows.remove(&self.id);
+        }
+    }
+}
+
+impl AsyncRead for DatagramFlow {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Poll::Ready(datagram) = this.receiver.poll_recv(cx) {
+            if let Some(datagram) = datagram {
+                let n = buf.remaining().min(datagram.len());
+                buf.put_slice(&datagram[..n]);
+            }
+
+            return Poll::Ready(Ok(()));
+        }
+
+        // nothing is sent on the flow stream, so any result means flow was closed
+        let mut probe = [0; 1];
+        let mut probe = ReadBuf::new(&mut probe);
+        match Pin::new(&mut this.stream).poll_read(cx, &mut probe) {
+            Poll::Ready(res) => Poll::Ready(res),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for DatagramFlow {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut datagram = BytesMut::with_capacity(buf.len() + 4);
+        datagram.put_u32(self.id);
+        datagram.put_slice(buf);
+
+        match self.connection.send_datagram(datagram.freeze()) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(quinn::SendDatagramError::TooLarge) => {
+                eprintln!("Dropping {} bytes datagram, too large for QUIC", buf.len());
+                Poll::Ready(Ok(buf.len()))
+            }
+            Err(e) => Poll::Ready(Err(io::Error::other(e))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
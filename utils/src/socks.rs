@@ -0,0 +1,142 @@
+use color_eyre::Result;
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const VERSION: u8 = 0x05;
+pub const NO_AUTH: u8 = 0x00;
+pub const USER_PASS: u8 = 0x02;
+pub const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+pub const CONNECT: u8 = 0x01;
+pub const UDP_ASSOCIATE: u8 = 0x03;
+
+pub const IPV4: u8 = 0x01;
+pub const DOMAIN: u8 = 0x03;
+pub const IPV6: u8 = 0x04;
+
+/// Reply codes, local forward tunnels are answered with them as well
+pub const SUCCEEDED: u8 = 0x00;
+pub const GENERAL_FAILURE: u8 = 0x01;
+pub const NOT_ALLOWED: u8 = 0x02;
+pub const HOST_UNREACHABLE: u8 = 0x04;
+pub const CONNECTION_REFUSED: u8 = 0x05;
+pub const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+/// Size of UDP request header before the address (reserved and fragment)
+pub const UDP_HEADER_SIZE: usize = 3;
+
+/// Address of SOCKS request, domains are resolved by the server
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Address {
+    pub fn unspecified() -> Self {
+        Address::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))
+    }
+
+    pub fn host(&self) -> String {
+        match self {
+            Address::Ip(addr) => addr.ip().to_string(),
+            Address::Domain(host, _) => host.clone(),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Address::Ip(addr) => addr.port(),
+            Address::Domain(_, port) => *port,
+        }
+    }
+
+    /// Reads address type, address and port of a request
+    pub async fn read<T>(stream: &mut T) -> Result<Self>
+    where
+        T: AsyncRead + Unpin,
+    {
+        let mut buf = vec![stream.read_u8().await?];
+        let len = match buf[0] {
+            IPV4 => 4,
+            IPV6 => 16,
+            DOMAIN => {
+                let len = stream.read_u8().await?;
+                buf.push(len);
+                len as usize
+            }
+            _ => color_eyre::eyre::bail!("Invalid SOCKS address type: {}", buf[0]),
+        };
+
+        let start = buf.len();
+        buf.resize(start + len + 2, 0);
+        stream.read_exact(&mut buf[start..]).await?;
+
+        Ok(Self::decode(&buf)?.0)
+    }
+
+    /// Parses address from the start of `data`, returns it with its length
+    pub fn decode(data: &[u8]) -> Result<(Self, usize)> {
+        let end = match data.first() {
+            Some(&IPV4) => 1 + 4,
+            Some(&IPV6) => 1 + 16,
+            Some(&DOMAIN) if data.len() > 1 => 2 + data[1] as usize,
+            _ => color_eyre::eyre::bail!("Invalid SOCKS address"),
+        };
+        if data.len() < end + 2 {
+            color_eyre::eyre::bail!("Invalid SOCKS address");
+        }
+
+        let port = u16::from_be_bytes([data[end], data[end + 1]]);
+        let address = match data[0] {
+            IPV4 => {
+                let ip: [u8; 4] = data[1..end].try_into()?;
+                Address::Ip(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+            }
+            IPV6 => {
+                let ip: [u8; 16] = data[1..end].try_into()?;
+                Address::Ip(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+            }
+            _ => Address::Domain(String::from_utf8(data[2..end].to_vec())?, port),
+        };
+
+        Ok((address, end + 2))
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Address::Ip(SocketAddr::V4(addr)) => {
+                buf.push(IPV4);
+                buf.extend_from_slice(&addr.ip().octets());
+            }
+            Address::Ip(SocketAddr::V6(addr)) => {
+                buf.push(IPV6);
+                buf.extend_from_slice(&addr.ip().octets());
+            }
+            Address::Domain(host, _) => {
+                if host.len() > u8::MAX as usize {
+                    color_eyre::eyre::bail!("Hostname too long: {}", host);
+                }
+
+                buf.push(DOMAIN);
+                buf.push(host.len() as u8);
+                buf.extend_from_slice(host.as_bytes());
+            }
+        }
+
+        buf.extend_from_slice(&self.port().to_be_bytes());
+        Ok(())
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Ip(addr) => write!(f, "{}", addr),
+            Address::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{bandwidth::Limits, CopyOptions, BUFFER_SIZE};
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::{io::Interest, net::TcpStream};
+
+/// Kernel pipe both directions are spliced through, so forwarded data never
+/// gets copied to userspace
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            read: unsafe { OwnedFd::from_raw_fd(fds[0]) },
+            write: unsafe { OwnedFd::from_raw_fd(fds[1]) },
+        })
+    }
+}
+
+/// Last time any data went through, shared by both directions
+struct Activity(Mutex<Instant>);
+
+impl Activity {
+    fn touch(&self) {
+        if let Ok(mut last) = self.0.lock() {
+            *last = Instant::now();
+        }
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.0.lock().map(|last| last.elapsed()).unwrap_or_default()
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// Forwards both directions between plain TCP sockets with splice(2), same
+/// half-close, idle timeout and bandwidth limits behaviour as the userspace copy
+pub async fn copy_bidirectional(
+    stream1: &TcpStream,
+    stream2: &TcpStream,
+    options: &CopyOptions,
+) -> io::Result<()> {
+    let activity = Activity(Mutex::new(Instant::now()));
+    let copy = async {
+        tokio::try_join!(
+            copy_one(stream1, stream2, &activity, &options.limits),
+            copy_one(stream2, stream1, &activity, &options.limits)
+        )
+    };
+
+    match options.idle_timeout {
+        Some(idle_timeout) => {
+            tokio::select! {
+                res = copy => res.map(|_| ()),
+                _ = wait_idle(&activity, idle_timeout) => {
+                    println!("Closing connection idle for {:?}", idle_timeout);
+                    if let Some(on_idle) = options.on_idle {
+                        on_idle();
+                    }
+                    Ok(())
+                }
+            }
+        }
+        None => copy.await.map(|_| ()),
+    }
+}
+
+async fn copy_one(
+    from: &TcpStream,
+    to: &TcpStream,
+    activity: &Activity,
+    limits: &Limits,
+) -> io::Result<()> {
+    let pipe = Pipe::new()?;
+
+    loop {
+        // pipe is always drained below, so EAGAIN here only means socket is empty
+        let n = from
+            .async_io(Interest::READABLE, || {
+                splice(from.as_raw_fd(), pipe.write.as_raw_fd(), BUFFER_SIZE)
+            })
+            .await?;
+
+        if n == 0 {
+            // half-close, the other direction keeps going
+            if unsafe { libc::shutdown(to.as_raw_fd(), libc::SHUT_WR) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            return Ok(());
+        }
+
+        limits.consume(n).await;
+
+        let mut left = n;
+        while left > 0 {
+            let written = to
+                .async_io(Interest::WRITABLE, || {
+                    splice(pipe.read.as_raw_fd(), to.as_raw_fd(), left)
+                })
+                .await?;
+
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            left -= written;
+        }
+
+        activity.touch();
+    }
+}
+
+async fn wait_idle(activity: &Activity, idle_timeout: Duration) {
+    loop {
+        let idle_for = activity.idle_for();
+        if idle_for >= idle_timeout {
+            return;
+        }
+
+        tokio::time::sleep(idle_timeout - idle_for).await;
+    }
+}
@@ -0,0 +1,236 @@
+use color_eyre::Result;
+use futures_util::{Sink, Stream};
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    time::Instant,
+};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+/// Byte stream carried in binary WebSocket messages, so tunnels can pass
+/// through proxies which only allow HTTP. Empty binary message marks end of
+/// one direction (half-close), the other one stays open.
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    read_eof: bool,
+    write_eof: bool,
+}
+
+impl<S> WsStream<S> {
+    fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            read_eof: false,
+            write_eof: false,
+        }
+    }
+}
+
+/// Upgrades already connected stream to WebSocket (client side)
+pub async fn connect(stream: TcpStream, host: &str, port: u16) -> Result<WsStream<TcpStream>> {
+    let url = format!("ws://{}:{}/", host, port);
+    let (inner, _) = tokio_tungstenite::client_async(url, stream).await?;
+
+    Ok(WsStream::new(inner))
+}
+
+/// Accepts WebSocket upgrade request (server side)
+pub async fn accept(stream: TcpStream) -> Result<WsStream<TcpStream>> {
+    let inner = tokio_tungstenite::accept_async(stream).await?;
+
+    Ok(WsStream::new(inner))
+}
+
+/// Milliseconds to wait for enough of the preamble to arrive, whatever came
+/// until then decides
+const PEEK_TIMEOUT: u64 = 500;
+const PEEK_INTERVAL: u64 = 10;
+
+/// Checks (without consuming anything) if connection starts with HTTP request
+/// instead of binary preamble. Binary preamble of port 18245 starts with
+/// `GE` too, so with `code` (sent in clear without noise) the one followed by
+/// our code is still taken as binary. Preamble can come in more segments, so
+/// it's peeked again until there's enough of it.
+pub async fn is_upgrade(stream: &TcpStream, code: Option<u64>) -> Result<bool> {
+    let mut buf = [0; 10];
+    let deadline = Instant::now() + Duration::from_millis(PEEK_TIMEOUT);
+    let n = loop {
+        let n = stream.peek(&mut buf).await?;
+        if n == 0 {
+            color_eyre::eyre::bail!("Connection closed");
+        }
+
+        // code is needed only when it could be preamble of port 18245
+        let get = b"GET ".starts_with(&buf[..n.min(4)]);
+        let needed = match code {
+            Some(_) if get => buf.len(),
+            None if get => 4,
+            _ => n,
+        };
+        if n >= needed || Instant::now() >= deadline {
+            break n;
+        }
+
+        tokio::time::sleep(Duration::from_millis(PEEK_INTERVAL)).await;
+    };
+
+    let preamble = code.is_some_and(|code| n == buf.len() && buf[2..] == code.to_be_bytes());
+    Ok(buf[..n].starts_with(b"GET ") && !preamble)
+}
+
+fn to_io_error<E>(e: E) -> io::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    io::Error::other(e)
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.read_pos < self.read_buf.len() {
+                let n = buf.remaining().min(self.read_buf.len() - self.read_pos);
+                buf.put_slice(&self.read_buf[self.read_pos..self.read_pos + n]);
+                self.read_pos += n;
+
+                return Poll::Ready(Ok(()));
+            }
+
+            if self.read_eof {
+                return Poll::Ready(Ok(()));
+            }
+
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) if data.is_empty() => {
+                    self.read_eof = true;
+                }
+                Some(Ok(Message::Binary(data))) => {
+                    self.read_buf = data;
+                    self.read_pos = 0;
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Err(to_io_error(e))),
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // empty message would be taken as EOF
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(to_io_error)?;
+        Pin::new(&mut self.inner)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(to_io_error)?;
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(to_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.write_eof {
+            ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(to_io_error)?;
+            Pin::new(&mut self.inner)
+                .start_send(Message::Binary(Vec::new()))
+                .map_err(to_io_error)?;
+            self.write_eof = true;
+        }
+
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(to_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    /// Client and server side of one loopback connection
+    async fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        (client, server)
+    }
+
+    /// Writes `parts` as separate segments with a pause between them
+    async fn write_split(mut client: TcpStream, parts: Vec<Vec<u8>>) -> TcpStream {
+        for part in parts {
+            client.write_all(&part).await.unwrap();
+            client.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        client
+    }
+
+    #[tokio::test]
+    async fn split_request_is_upgrade() {
+        let (client, server) = pair().await;
+        let parts = vec![b"GE".to_vec(), b"T / HTTP/1.1\r\n".to_vec()];
+        let writer = tokio::spawn(write_split(client, parts));
+
+        assert!(is_upgrade(&server, Some(42)).await.unwrap());
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn split_preamble_is_not_upgrade() {
+        // port 18245 and code starting with `T ` look like `GET ` at first
+        let code = u64::from_be_bytes(*b"T secret");
+        let preamble = [18245u16.to_be_bytes().to_vec(), code.to_be_bytes().to_vec()].concat();
+
+        let (client, server) = pair().await;
+        let parts = vec![preamble[..4].to_vec(), preamble[4..].to_vec()];
+        let writer = tokio::spawn(write_split(client, parts));
+
+        assert!(!is_upgrade(&server, Some(code)).await.unwrap());
+        writer.await.unwrap();
+
+        let (client, server) = pair().await;
+        let parts = vec![vec![0], 1337u16.to_be_bytes()[1..].to_vec()];
+        let writer = tokio::spawn(write_split(client, parts));
+
+        assert!(!is_upgrade(&server, Some(code)).await.unwrap());
+        writer.await.unwrap();
+    }
+}
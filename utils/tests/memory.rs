@@ -0,0 +1,156 @@
+//! Memory held by idle UDP flows and TCP connections, ignored by default.
+//! Run it in release mode (TCP connections need about 4000 file descriptors):
+//!
+//! `cargo test --release --test memory -- --ignored --nocapture --test-threads 1`
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+use udpflow::UdpStreamRemote;
+use utils::{framing::LengthFramed, CopyOptions, MultiStream, PortType};
+
+/// Idle flows measured at once
+const FLOWS: usize = 500;
+
+/// Idle TCP connections measured at once
+const CONNECTIONS: usize = 2000;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// System allocator counting bytes currently allocated
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// One message each way through every flow, then measures what the copy
+/// loops hold while the flows sit idle. Returns bytes per flow together with
+/// the flows, they have to stay open until all measurements are done.
+async fn measure<P>(flows: Vec<(MultiStream, P)>) -> (usize, Vec<(DuplexStream, P)>)
+where
+    P: AsyncRead + AsyncWrite + Unpin,
+{
+    let count = flows.len();
+    let mut apps = Vec::new();
+    let mut peers = Vec::new();
+    let before = ALLOCATED.load(Ordering::Relaxed);
+
+    for (tunnel, peer) in flows {
+        let (app, local) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let options = CopyOptions {
+                idle_timeout: Some(Duration::from_secs(600)),
+                ..Default::default()
+            };
+            tunnel.copy_bidirectional(local, &options).await
+        });
+        apps.push(app);
+        peers.push(peer);
+    }
+
+    let mut buf = [0u8; 16];
+    for (app, peer) in apps.iter_mut().zip(peers.iter_mut()) {
+        app.write_all(b"ping").await.unwrap();
+        assert_eq!(peer.read(&mut buf).await.unwrap(), 4);
+        peer.write_all(b"pong").await.unwrap();
+        peer.flush().await.unwrap();
+        assert_eq!(app.read(&mut buf).await.unwrap(), 4);
+    }
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let held = ALLOCATED.load(Ordering::Relaxed).saturating_sub(before);
+    (held / count, apps.into_iter().zip(peers).collect())
+}
+
+/// Flows over TCP tunnel, datagrams are length-prefixed
+async fn tcp_flows() -> Vec<(MultiStream, LengthFramed<TcpStream>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut flows = Vec::new();
+    for _ in 0..FLOWS {
+        let tunnel = TcpStream::connect(addr).await.unwrap();
+        let (peer, _) = listener.accept().await.unwrap();
+        flows.push((
+            MultiStream::Tcp(tunnel).framed(&PortType::Udp),
+            LengthFramed::new(peer),
+        ));
+    }
+
+    flows
+}
+
+/// Plain TCP connections, tunnel and its peer on the server
+async fn tcp_connections() -> Vec<(MultiStream, TcpStream)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut connections = Vec::new();
+    for _ in 0..CONNECTIONS {
+        let tunnel = TcpStream::connect(addr).await.unwrap();
+        let (peer, _) = listener.accept().await.unwrap();
+        connections.push((MultiStream::Tcp(tunnel), peer));
+    }
+
+    connections
+}
+
+/// Flows over UDP tunnel, datagrams are sent as datagram frames
+async fn udp_flows() -> Vec<(MultiStream, MultiStream)> {
+    let mut flows = Vec::new();
+    for _ in 0..FLOWS {
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+
+        flows.push((
+            MultiStream::UdpRemote(UdpStreamRemote::new(a, b_addr)).framed(&PortType::Udp),
+            MultiStream::UdpRemote(UdpStreamRemote::new(b, a_addr)).framed(&PortType::Udp),
+        ));
+    }
+
+    flows
+}
+
+#[tokio::test]
+#[ignore]
+async fn idle_udp_flows() {
+    utils::init_udpflow();
+
+    let (per_flow, _tcp_flows) = measure(tcp_flows().await).await;
+    println!("UDP over TCP: {} bytes per idle flow", per_flow);
+
+    let (per_flow, _udp_flows) = measure(udp_flows().await).await;
+    println!("UDP over UDP: {} bytes per idle flow", per_flow);
+}
+
+#[tokio::test]
+#[ignore]
+async fn idle_tcp_connections() {
+    let (per_connection, _connections) = measure(tcp_connections().await).await;
+    println!("TCP: {} bytes per idle connection", per_connection);
+}
@@ -0,0 +1,249 @@
+//! Throughput benchmarks of copying and compression, ignored by default.
+//! Run them in release mode:
+//!
+//! `cargo test --release --test throughput -- --ignored --nocapture --test-threads 1`
+
+use rand::{seq::SliceRandom, Rng};
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+use utils::{
+    compression::{Compressed, Compression},
+    CopyOptions, MultiStream, BUFFER_SIZE,
+};
+
+/// Bytes sent through every measured connection
+const TOTAL: usize = 1 << 30;
+
+/// Bytes compressed in every compression run
+const COMPRESSED_TOTAL: usize = 64 << 20;
+
+/// Accepts one connection and reads it to the end, returns bytes read
+async fn sink() -> (String, JoinHandle<usize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    let task = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        let mut total = 0;
+        loop {
+            match stream.read(&mut buf).await.unwrap() {
+                0 => return total,
+                n => total += n,
+            }
+        }
+    });
+
+    (addr, task)
+}
+
+/// Forwards one connection to `target` the way tunnels are forwarded,
+/// with splice(2) or with the userspace copy loop
+async fn proxy(target: String, splice: bool) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    tokio::spawn(async move {
+        let (local, _) = listener.accept().await.unwrap();
+        let tunnel = MultiStream::Tcp(TcpStream::connect(target).await.unwrap());
+        let options = CopyOptions::default();
+
+        match splice {
+            true => tunnel.copy_bidirectional_tcp(local, &options).await,
+            false => tunnel.copy_bidirectional(local, &options).await,
+        }
+        .unwrap();
+    });
+
+    addr
+}
+
+/// CPU seconds used by the whole process so far (sender and sink included,
+/// they cost the same in both runs)
+#[cfg(target_os = "linux")]
+fn cpu_time() -> f64 {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+
+    let seconds = |t: libc::timeval| t.tv_sec as f64 + t.tv_usec as f64 / 1e6;
+    seconds(usage.ru_utime) + seconds(usage.ru_stime)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cpu_time() -> f64 {
+    0.0
+}
+
+/// Sends `TOTAL` bytes through the proxy, returns MiB/s and CPU seconds
+async fn measure(splice: bool) -> (f64, f64) {
+    let (sink_addr, received) = sink().await;
+    let proxy_addr = proxy(sink_addr, splice).await;
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    let chunk = vec![0x5a; BUFFER_SIZE];
+    let start = Instant::now();
+    let cpu = cpu_time();
+
+    for _ in 0..TOTAL / chunk.len() {
+        stream.write_all(&chunk).await.unwrap();
+    }
+    stream.shutdown().await.unwrap();
+    assert_eq!(received.await.unwrap(), TOTAL);
+
+    let throughput = TOTAL as f64 / (1 << 20) as f64 / start.elapsed().as_secs_f64();
+    (throughput, cpu_time() - cpu)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn splice_vs_copy_loop() {
+    let (throughput, cpu) = measure(false).await;
+    println!("copy loop: {:.0} MiB/s, {:.2}s CPU", throughput, cpu);
+
+    let (throughput, cpu) = measure(true).await;
+    println!("splice:    {:.0} MiB/s, {:.2}s CPU", throughput, cpu);
+}
+
+/// In-memory pipe end counting bytes written to it
+struct Counted {
+    inner: DuplexStream,
+    written: Arc<AtomicUsize>,
+}
+
+impl AsyncRead for Counted {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Counted {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            this.written.fetch_add(n, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Random words, compressible about like logs or JSON
+fn text() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let words: Vec<String> = (0..1000)
+        .map(|_| {
+            let len = rng.gen_range(2..10);
+            (0..len)
+                .map(|_| rng.gen_range(b'a'..=b'z') as char)
+                .collect()
+        })
+        .collect();
+
+    let mut text = Vec::with_capacity(COMPRESSED_TOTAL);
+    while text.len() < COMPRESSED_TOTAL {
+        text.extend_from_slice(words.choose(&mut rng).unwrap().as_bytes());
+        text.push(b' ');
+    }
+    text.truncate(COMPRESSED_TOTAL);
+    text
+}
+
+fn random() -> Vec<u8> {
+    let mut data = vec![0u8; COMPRESSED_TOTAL];
+    rand::thread_rng().fill(&mut data[..]);
+    data
+}
+
+/// Writes `data` in `chunk` sized writes, each flushed like the copy loop
+/// does, and reads it back on the other side of the pipe. Returns ratio and
+/// MiB/s.
+async fn compress(data: &[u8], chunk: usize, compression: Option<Compression>) -> (f64, f64) {
+    let (a, b) = tokio::io::duplex(BUFFER_SIZE);
+    let written = Arc::new(AtomicUsize::new(0));
+    let a = Counted {
+        inner: a,
+        written: written.clone(),
+    };
+
+    let (mut writer, mut reader): (
+        Box<dyn AsyncWrite + Send + Unpin>,
+        Box<dyn AsyncRead + Send + Unpin>,
+    ) = match compression {
+        Some(compression) => (
+            Box::new(Compressed::new(a, compression)),
+            Box::new(Compressed::new(b, compression)),
+        ),
+        None => (Box::new(a), Box::new(b)),
+    };
+
+    let start = Instant::now();
+    let received = tokio::spawn(async move {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        let mut total = 0;
+        loop {
+            match reader.read(&mut buf).await.unwrap() {
+                0 => return total,
+                n => total += n,
+            }
+        }
+    });
+
+    for chunk in data.chunks(chunk) {
+        writer.write_all(chunk).await.unwrap();
+        writer.flush().await.unwrap();
+    }
+    writer.shutdown().await.unwrap();
+    assert_eq!(received.await.unwrap(), data.len());
+
+    let ratio = data.len() as f64 / written.load(Ordering::Relaxed) as f64;
+    let throughput = data.len() as f64 / (1 << 20) as f64 / start.elapsed().as_secs_f64();
+    (ratio, throughput)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn compression() {
+    for (name, data) in [("text", text()), ("random", random())] {
+        for chunk in [BUFFER_SIZE, 2048] {
+            let mut line = format!("{}, {}K chunks:", name, chunk / 1024);
+            for (label, compression) in [
+                ("none", None),
+                ("zstd", Some(Compression::Zstd)),
+                ("lz4", Some(Compression::Lz4)),
+            ] {
+                let (ratio, throughput) = compress(&data, chunk, compression).await;
+                line += &format!(" {} {:.2}x {:.0} MiB/s |", label, ratio, throughput);
+            }
+            println!("{}", line.trim_end_matches(" |"));
+        }
+    }
+}
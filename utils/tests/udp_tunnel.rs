@@ -0,0 +1,270 @@
+//! UDP tunnel type end to end over loopback: client handshake and framing
+//! against the same handshake and framing the connector uses
+
+use color_eyre::Result;
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    sync::mpsc,
+};
+use udpflow::UdpListener;
+use utils::{
+    framing::UdpHandshake, ConnectorAddr, ConnectorPort, CopyOptions, MultiStream, BUFFER_SIZE,
+};
+
+const CODE: u64 = 0x1234_5678_9abc_def0;
+const UDP_PORT: u16 = 1;
+const TCP_PORT: u16 = 2;
+const REQUEST: u64 = 7;
+
+/// Stand-in connector echoing every tunnel back, handshake results are
+/// reported on the returned channel
+async fn connector() -> (SocketAddr, mpsc::UnboundedReceiver<Result<UdpHandshake>>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let listener = UdpListener::new(socket);
+    let (results, results_rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            let (mut socket, _) = listener.accept(&mut buf).await.unwrap();
+            let results = results.clone();
+
+            tokio::spawn(async move {
+                let handshake = match UdpHandshake::receive(&mut socket).await {
+                    Ok(handshake) if handshake.code == CODE => handshake,
+                    Ok(_) => {
+                        let _ = results.send(Err(color_eyre::eyre::eyre!("Wrong code")));
+                        return;
+                    }
+                    Err(e) => {
+                        let _ = results.send(Err(e));
+                        return;
+                    }
+                };
+                let socket = handshake.accept(socket).await.unwrap();
+
+                let port_type = match handshake.port {
+                    UDP_PORT => utils::PortType::Udp,
+                    _ => utils::PortType::Tcp,
+                };
+                let _ = results.send(Ok(handshake));
+
+                let mut tunnel = MultiStream::UdpLocal(socket).framed(&port_type);
+                let mut buf = vec![0u8; BUFFER_SIZE];
+                loop {
+                    match tunnel.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => tunnel.write_all(&buf[..n]).await.unwrap(),
+                    }
+                }
+                let _ = tunnel.shutdown().await;
+            });
+        }
+    });
+
+    (addr, results_rx)
+}
+
+/// Relays datagrams between one client and the connector, the first `lost`
+/// datagrams coming back from the connector are dropped
+async fn lossy_relay(connector: SocketAddr, lost: usize) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    upstream.connect(connector).await.unwrap();
+
+    tokio::spawn(async move {
+        let mut client = None;
+        let mut dropped = 0;
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        let mut upstream_buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            tokio::select! {
+                res = socket.recv_from(&mut buf) => {
+                    let (n, from) = res.unwrap();
+                    client = Some(from);
+                    upstream.send(&buf[..n]).await.unwrap();
+                }
+                res = upstream.recv(&mut upstream_buf) => {
+                    let n = res.unwrap();
+                    if dropped < lost {
+                        dropped += 1;
+                        continue;
+                    }
+                    if let Some(client) = client {
+                        socket.send_to(&upstream_buf[..n], client).await.unwrap();
+                    }
+                }
+            }
+        }
+    });
+
+    addr
+}
+
+fn connector_addr(addr: SocketAddr) -> ConnectorAddr {
+    ConnectorAddr {
+        ip: addr.ip().to_string(),
+        port: addr.port(),
+        proxy: None,
+        quic_port: None,
+        quic_fingerprint: None,
+        punch_port: None,
+        noise: false,
+    }
+}
+
+fn port(port_remote: u16, port_type: &str) -> ConnectorPort {
+    serde_json::from_value(serde_json::json!({
+        "port_remote": port_remote,
+        "port_local": 0,
+        "local_ip": "127.0.0.1",
+        "port_type": port_type,
+        "tunnel_type": "Udp",
+    }))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn udp_over_udp_keeps_datagrams() {
+    let (addr, mut results) = connector().await;
+    let mut tunnel = MultiStream::connect_and_setup(
+        &connector_addr(addr),
+        &port(UDP_PORT, "Udp"),
+        CODE,
+        REQUEST,
+    )
+    .await
+    .unwrap();
+
+    let handshake = results.recv().await.unwrap().unwrap();
+    assert_eq!(handshake.port, UDP_PORT);
+    assert_eq!(handshake.request_id, REQUEST);
+    assert!(handshake.hostname.is_none());
+
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    for size in [1, 100, 1200, 8000] {
+        let datagram: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        tunnel.write_all(&datagram).await.unwrap();
+
+        let n = tunnel.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], &datagram[..]);
+    }
+}
+
+#[tokio::test]
+async fn tcp_over_udp_is_reliable() {
+    let (addr, mut results) = connector().await;
+    let tunnel = MultiStream::connect_and_setup(
+        &connector_addr(addr),
+        &port(TCP_PORT, "Tcp"),
+        CODE,
+        REQUEST,
+    )
+    .await
+    .unwrap();
+    assert_eq!(results.recv().await.unwrap().unwrap().port, TCP_PORT);
+
+    let data: Vec<u8> = (0..512 * 1024).map(|i| (i % 251) as u8).collect();
+    let (mut read, mut write) = tokio::io::split(tunnel);
+
+    let sent = data.clone();
+    tokio::spawn(async move {
+        write.write_all(&sent).await.unwrap();
+        write.shutdown().await.unwrap();
+    });
+
+    let mut received = Vec::new();
+    read.read_to_end(&mut received).await.unwrap();
+    assert!(received == data);
+}
+
+#[tokio::test]
+async fn lost_ack_is_sent_again() {
+    for (port_remote, port_type) in [(UDP_PORT, "Udp"), (TCP_PORT, "Tcp")] {
+        let (addr, mut results) = connector().await;
+        let relay = lossy_relay(addr, 1).await;
+        let mut tunnel = MultiStream::connect_and_setup(
+            &connector_addr(relay),
+            &port(port_remote, port_type),
+            CODE,
+            REQUEST,
+        )
+        .await
+        .unwrap();
+        assert_eq!(results.recv().await.unwrap().unwrap().port, port_remote);
+
+        tunnel.write_all(b"ping").await.unwrap();
+        tunnel.flush().await.unwrap();
+        let mut buf = [0u8; 4];
+        tunnel.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
+
+#[tokio::test]
+async fn wrong_code_is_not_acknowledged() {
+    let (addr, mut results) = connector().await;
+    let tunnel = MultiStream::connect_and_setup(
+        &connector_addr(addr),
+        &port(UDP_PORT, "Udp"),
+        !CODE,
+        REQUEST,
+    )
+    .await;
+
+    assert!(tunnel.is_err());
+    assert!(results.recv().await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn oversized_handshake_is_rejected() {
+    let (addr, mut results) = connector().await;
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut datagram = UdpHandshake {
+        port: UDP_PORT,
+        code: CODE,
+        connection_id: 1,
+        request_id: REQUEST,
+        hostname: None,
+    }
+    .encode()
+    .unwrap();
+    datagram.resize(2000, b'a');
+    socket.send_to(&datagram, addr).await.unwrap();
+    assert!(results.recv().await.unwrap().is_err());
+
+    // connector keeps accepting other flows
+    MultiStream::connect_and_setup(&connector_addr(addr), &port(UDP_PORT, "Udp"), CODE, REQUEST)
+        .await
+        .unwrap();
+    assert!(results.recv().await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn datagram_flow_ends_with_either_side() {
+    let (addr, _results) = connector().await;
+    let tunnel = MultiStream::connect_and_setup(
+        &connector_addr(addr),
+        &port(UDP_PORT, "Udp"),
+        CODE,
+        REQUEST,
+    )
+    .await
+    .unwrap();
+
+    // local side closes, tunnel stays open, no half-close for datagrams
+    let (local, closed) = tokio::io::duplex(BUFFER_SIZE);
+    drop(closed);
+
+    let options = CopyOptions::default();
+    let copy = tunnel.copy_bidirectional(local, &options);
+    tokio::time::timeout(std::time::Duration::from_secs(1), copy)
+        .await
+        .expect("flow kept open after EOF")
+        .unwrap();
+}